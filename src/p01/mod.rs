@@ -3,15 +3,65 @@ use std::{
     net::{TcpListener, TcpStream},
 };
 
-use serde::{Deserialize, Serialize};
-use serde_json::Number as JsonNumber;
+use serde::Serialize;
+use serde_json::{Number as JsonNumber, Value as JsonValue};
 
-#[derive(Deserialize, Debug)]
+// Whitespace (as defined by JSON) between the closing `}` and the newline is accepted when set,
+// otherwise the request is malformed
+const ALLOW_TRAILING_WHITESPACE: bool = true;
+// A final line the client didn't terminate with `\n` before closing its side is processed as a
+// request when set, otherwise it gets a malformed response
+const ACCEPT_UNTERMINATED_LAST_LINE: bool = false;
+
+#[derive(Debug)]
 struct Request {
     method: String,
     number: JsonNumber,
 }
 
+#[derive(Debug, PartialEq)]
+enum Malformed {
+    Unterminated,
+    TrailingWhitespace,
+    InvalidJson,
+    NotAnObject,
+    MissingMethod,
+    MethodNotString,
+    UnknownMethod,
+    MissingNumber,
+    NumberNotNumber,
+}
+
+fn parse_request(line: &[u8]) -> Result<Request, Malformed> {
+    let line = match line.strip_suffix(b"\n") {
+        Some(line) => line,
+        None if ACCEPT_UNTERMINATED_LAST_LINE => line,
+        None => return Err(Malformed::Unterminated),
+    };
+    if !ALLOW_TRAILING_WHITESPACE && line.last().is_some_and(|b| b" \t\r".contains(b)) {
+        return Err(Malformed::TrailingWhitespace);
+    }
+    let JsonValue::Object(mut object) =
+        serde_json::from_slice(line).map_err(|_| Malformed::InvalidJson)?
+    else {
+        return Err(Malformed::NotAnObject);
+    };
+    let method = match object.remove("method") {
+        Some(JsonValue::String(method)) => method,
+        Some(_) => return Err(Malformed::MethodNotString),
+        None => return Err(Malformed::MissingMethod),
+    };
+    if method != "isPrime" {
+        return Err(Malformed::UnknownMethod);
+    }
+    let number = match object.remove("number") {
+        Some(JsonValue::Number(number)) => number,
+        Some(_) => return Err(Malformed::NumberNotNumber),
+        None => return Err(Malformed::MissingNumber),
+    };
+    Ok(Request { method, number })
+}
+
 #[derive(Serialize)]
 struct Response {
     method: String,
@@ -37,32 +87,23 @@ pub fn main() {
                     stream.shutdown(std::net::Shutdown::Both).unwrap();
                     break;
                 }
-                let request: Request = match serde_json::from_slice(&bytes[..bytes.len() - 1]) {
+                let request = match parse_request(&bytes) {
                     Ok(request) => request,
-                    Err(e) => {
-                        eprintln!("Error parsing request: {:?}", e);
+                    Err(reason) => {
+                        eprintln!("Malformed request ({:?}): {:?}", reason, bytes);
                         send_malformed_and_close(&mut stream);
                         break;
                     }
                 };
 
                 eprintln!("Received request: {:?}", request);
-                match request.method.as_str() {
-                    "isPrime" => {
-                        let mut response = serde_json::to_vec(&Response {
-                            method: "isPrime".to_string(),
-                            prime: is_prime(request.number),
-                        })
-                        .unwrap();
-                        response.push(b'\n');
-                        stream.write_all(&response).unwrap();
-                    }
-                    _ => {
-                        eprintln!("Invalid method: {:?}", request.method);
-                        send_malformed_and_close(&mut stream);
-                        break;
-                    }
-                };
+                let mut response = serde_json::to_vec(&Response {
+                    method: request.method,
+                    prime: is_prime(request.number),
+                })
+                .unwrap();
+                response.push(b'\n');
+                stream.write_all(&response).unwrap();
             }
         });
     }
@@ -85,3 +126,63 @@ fn is_prime(value: JsonNumber) -> bool {
     }
     true
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_request_rules() {
+        let trailing_whitespace = if ALLOW_TRAILING_WHITESPACE {
+            Ok(())
+        } else {
+            Err(Malformed::TrailingWhitespace)
+        };
+        let unterminated = if ACCEPT_UNTERMINATED_LAST_LINE {
+            Ok(())
+        } else {
+            Err(Malformed::Unterminated)
+        };
+        let cases: &[(&[u8], Result<(), Malformed>)] = &[
+            (b"{\"method\":\"isPrime\",\"number\":7}\n", Ok(())),
+            (
+                b"{\"number\":7.5,\"method\":\"isPrime\",\"extra\":[]}\n",
+                Ok(()),
+            ),
+            (b"{\"method\":\"isPrime\",\"number\":7}", unterminated),
+            (
+                b"{\"method\":\"isPrime\",\"number\":7} \t\n",
+                trailing_whitespace,
+            ),
+            (
+                b"{\"method\":\"isPrime\",\"number\":7}x\n",
+                Err(Malformed::InvalidJson),
+            ),
+            (b"\n", Err(Malformed::InvalidJson)),
+            (b"[1,2]\n", Err(Malformed::NotAnObject)),
+            (b"\"isPrime\"\n", Err(Malformed::NotAnObject)),
+            (b"{\"number\":7}\n", Err(Malformed::MissingMethod)),
+            (
+                b"{\"method\":1,\"number\":7}\n",
+                Err(Malformed::MethodNotString),
+            ),
+            (
+                b"{\"method\":\"isprime\",\"number\":7}\n",
+                Err(Malformed::UnknownMethod),
+            ),
+            (b"{\"method\":\"isPrime\"}\n", Err(Malformed::MissingNumber)),
+            (
+                b"{\"method\":\"isPrime\",\"number\":\"7\"}\n",
+                Err(Malformed::NumberNotNumber),
+            ),
+            (
+                b"{\"method\":\"isPrime\",\"number\":null}\n",
+                Err(Malformed::NumberNotNumber),
+            ),
+        ];
+        for (input, expected) in cases {
+            let result = parse_request(input).map(|_| ());
+            assert_eq!(&result, expected, "{:?}", String::from_utf8_lossy(input));
+        }
+    }
+}
@@ -1,12 +1,51 @@
+mod prices;
+
 use std::{
+    collections::HashMap,
     io::{BufReader, Read, Write},
     net::TcpListener,
+    path::PathBuf,
     sync::{Arc, Mutex},
+    time::Duration,
 };
 
+use prices::{DuplicatePolicy, Prices};
+
+// The spec leaves inserting the same timestamp twice undefined. `Reject` closes the session.
+const DUPLICATE_POLICY: DuplicatePolicy = DuplicatePolicy::KeepAll;
+// Opt-in opcodes on top of `I` and `Q`, all taking the same 9-byte message with an inclusive
+// timestamp range [num1, num2]. Responses are big-endian, 0 for an empty range:
+//   `N` min price (i32), `X` max price (i32), `E` median price (i32),
+//   `C` number of prices (u64), `S` sum of prices (i64), `R` delete, number of removed prices (u64)
+const EXTENDED_OPCODES: bool = false;
+// Opt-in `U` opcode switching the session to a series shared by all connections, named by the
+// remaining 8 bytes of the message (trailing NUL bytes stripped). Until then, the session uses
+// its own private series, as in the standard protocol.
+const SHARED_SERIES: bool = false;
+// Shared series are loaded from and periodically written to this directory, when set
+const SNAPSHOT_DIR: Option<&str> = None;
+const SNAPSHOT_INTERVAL: Duration = Duration::from_secs(10);
+
+type Series = Arc<Mutex<Prices>>;
+
 pub fn main() {
     let listener = TcpListener::bind("0.0.0.0:1200").unwrap();
-    let lock: Arc<Mutex<()>> = Default::default();
+    let registry: Arc<Mutex<HashMap<Vec<u8>, Series>>> = Default::default();
+    if SHARED_SERIES && SNAPSHOT_DIR.is_some() {
+        let registry = Arc::clone(&registry);
+        std::thread::spawn(move || loop {
+            std::thread::sleep(SNAPSHOT_INTERVAL);
+            let all = registry
+                .lock()
+                .unwrap()
+                .iter()
+                .map(|(name, series)| (name.clone(), Arc::clone(series)))
+                .collect::<Vec<_>>();
+            for (name, series) in all {
+                save_snapshot(&name, &series);
+            }
+        });
+    }
     for incoming in listener.into_incoming() {
         let mut stream = match incoming {
             Ok(stream) => stream,
@@ -15,9 +54,9 @@ pub fn main() {
                 continue;
             }
         };
-        let lock = Arc::clone(&lock);
+        let registry = Arc::clone(&registry);
         std::thread::spawn(move || {
-            let mut prices = Vec::<(i32, i32)>::new();
+            let mut series: Series = Arc::new(Mutex::new(Prices::new(DUPLICATE_POLICY)));
             let mut buffer = BufReader::new(stream.try_clone().unwrap());
             loop {
                 let mut bytes = [0; 9];
@@ -37,28 +76,45 @@ pub fn main() {
 
                 match op {
                     b'I' => {
-                        prices.push((num1, num2));
+                        if let Err(e) = series.lock().unwrap().insert(num1, num2) {
+                            eprintln!("Rejected insert at {}: {:?}", num1, e);
+                            stream.shutdown(std::net::Shutdown::Both).unwrap();
+                            break;
+                        }
                     }
                     b'Q' => {
-                        prices.sort();
-                        let start = prices.partition_point(|(timestamp, _)| *timestamp < num1);
-                        let end = prices.partition_point(|(timestamp, _)| *timestamp <= num2);
-                        let n = end as i128 - start as i128;
-
-                        let guard = lock.lock().unwrap();
-                        let mean = if n <= 0 {
-                            0
-                        } else {
-                            let sum: i128 = prices[start..end]
-                                .iter()
-                                .map(|(_, price)| *price as i128)
-                                .sum();
-                            (sum / n) as i32
-                        };
+                        let mean = series.lock().unwrap().mean(num1, num2);
                         eprintln!("Mean: {}", mean);
-                        drop(guard);
                         stream.write_all(&(mean).to_be_bytes()).unwrap();
                     }
+                    b'N' | b'X' | b'E' | b'C' | b'S' | b'R' if EXTENDED_OPCODES => {
+                        let mut prices = series.lock().unwrap();
+                        let response = match op {
+                            b'N' => prices.aggregate(num1, num2).min().to_be_bytes().to_vec(),
+                            b'X' => prices.aggregate(num1, num2).max().to_be_bytes().to_vec(),
+                            b'E' => prices.median(num1, num2).to_be_bytes().to_vec(),
+                            b'C' => prices.aggregate(num1, num2).count.to_be_bytes().to_vec(),
+                            b'S' => prices.aggregate(num1, num2).sum.to_be_bytes().to_vec(),
+                            _ => prices.delete(num1, num2).to_be_bytes().to_vec(),
+                        };
+                        drop(prices);
+                        eprintln!("{}: {:?}", op as char, response);
+                        stream.write_all(&response).unwrap();
+                    }
+                    b'U' if SHARED_SERIES => {
+                        let mut name = rest.to_vec();
+                        while name.last() == Some(&0) {
+                            name.pop();
+                        }
+                        eprintln!("Using series {:?}", String::from_utf8_lossy(&name));
+                        series = Arc::clone(
+                            registry
+                                .lock()
+                                .unwrap()
+                                .entry(name)
+                                .or_insert_with_key(|name| load_snapshot(name)),
+                        );
+                    }
                     _ => {
                         eprintln!("Invalid operation: {}", op);
                         stream.shutdown(std::net::Shutdown::Both).unwrap();
@@ -69,3 +125,52 @@ pub fn main() {
         });
     }
 }
+
+fn snapshot_path(name: &[u8]) -> Option<PathBuf> {
+    let hex_name = name.iter().map(|b| format!("{:02x}", b)).collect::<String>();
+    SNAPSHOT_DIR.map(|dir| PathBuf::from(dir).join(format!("series-{}.bin", hex_name)))
+}
+
+// snapshots are a sequence of big-endian (timestamp, price) i32 pairs
+fn load_snapshot(name: &[u8]) -> Series {
+    let mut prices = Prices::new(DUPLICATE_POLICY);
+    if let Some(path) = snapshot_path(name) {
+        match std::fs::read(&path) {
+            Ok(data) => {
+                for entry in data.chunks_exact(8) {
+                    let timestamp = i32::from_be_bytes(entry[..4].try_into().unwrap());
+                    let price = i32::from_be_bytes(entry[4..].try_into().unwrap());
+                    let _ = prices.insert(timestamp, price);
+                }
+            }
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => {}
+            Err(e) => eprintln!("Error reading snapshot {:?}: {:?}", path, e),
+        }
+    }
+    Arc::new(Mutex::new(prices))
+}
+
+fn save_snapshot(name: &[u8], series: &Series) {
+    let Some(path) = snapshot_path(name) else { return; };
+    let data = series
+        .lock()
+        .unwrap()
+        .entries()
+        .into_iter()
+        .flat_map(|(timestamp, price)| {
+            timestamp
+                .to_be_bytes()
+                .into_iter()
+                .chain(price.to_be_bytes())
+        })
+        .collect::<Vec<_>>();
+    // write to a temporary file first, so that a crash never leaves a truncated snapshot behind
+    let tmp_path = path.with_extension("tmp");
+    let res: Result<(), std::io::Error> = try {
+        std::fs::write(&tmp_path, &data)?;
+        std::fs::rename(&tmp_path, &path)?;
+    };
+    if let Err(e) = res {
+        eprintln!("Error writing snapshot {:?}: {:?}", path, e);
+    }
+}
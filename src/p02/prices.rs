@@ -0,0 +1,512 @@
+// Treap keyed by timestamp, every node also holding an aggregate of the prices in its subtree.
+// Inserts and range queries are O(log n) expected, instead of re-sorting the whole session.
+
+const NIL: u32 = u32::MAX;
+
+#[allow(dead_code)]
+#[derive(Clone, Copy, Debug)]
+pub enum DuplicatePolicy {
+    KeepAll,
+    FirstWins,
+    LastWins,
+    Reject,
+}
+
+#[derive(Debug)]
+pub struct DuplicateTimestamp;
+
+#[derive(Clone, Copy)]
+pub struct Aggregate {
+    pub count: u64,
+    pub sum: i64,
+    min: i32,
+    max: i32,
+}
+
+impl Default for Aggregate {
+    fn default() -> Self {
+        Self {
+            count: 0,
+            sum: 0,
+            min: i32::MAX,
+            max: i32::MIN,
+        }
+    }
+}
+
+impl Aggregate {
+    fn single(price: i32) -> Self {
+        Self {
+            count: 1,
+            sum: price as i64,
+            min: price,
+            max: price,
+        }
+    }
+
+    fn add(&mut self, other: Aggregate) {
+        self.count += other.count;
+        self.sum += other.sum;
+        self.min = self.min.min(other.min);
+        self.max = self.max.max(other.max);
+    }
+
+    pub fn mean(&self) -> i32 {
+        if self.count == 0 {
+            0
+        } else {
+            (self.sum / self.count as i64) as i32
+        }
+    }
+
+    pub fn min(&self) -> i32 {
+        if self.count == 0 {
+            0
+        } else {
+            self.min
+        }
+    }
+
+    pub fn max(&self) -> i32 {
+        if self.count == 0 {
+            0
+        } else {
+            self.max
+        }
+    }
+}
+
+struct Node {
+    timestamp: i32,
+    price: i32,
+    priority: u64,
+    children: [u32; 2],
+    subtree: Aggregate,
+}
+
+pub struct Prices {
+    nodes: Vec<Node>,
+    free: Vec<u32>,
+    root: u32,
+    rng: u64,
+    duplicates: DuplicatePolicy,
+}
+
+impl Prices {
+    pub fn new(duplicates: DuplicatePolicy) -> Self {
+        Self {
+            nodes: Vec::new(),
+            free: Vec::new(),
+            root: NIL,
+            rng: 0x9E37_79B9_7F4A_7C15,
+            duplicates,
+        }
+    }
+
+    pub fn insert(&mut self, timestamp: i32, price: i32) -> Result<(), DuplicateTimestamp> {
+        let [left, mut same, right] = self.split_range(timestamp, timestamp);
+        let result = match (same, self.duplicates) {
+            (NIL, _) | (_, DuplicatePolicy::KeepAll) => {
+                let new = self.new_node(timestamp, price);
+                same = self.merge(same, new);
+                Ok(())
+            }
+            // all other policies keep at most one entry per timestamp
+            (_, DuplicatePolicy::FirstWins) => Ok(()),
+            (existing, DuplicatePolicy::LastWins) => {
+                self.nodes[existing as usize].price = price;
+                self.update(existing);
+                Ok(())
+            }
+            (_, DuplicatePolicy::Reject) => Err(DuplicateTimestamp),
+        };
+        self.join([left, same, right]);
+        result
+    }
+
+    pub fn aggregate(&mut self, from: i32, to: i32) -> Aggregate {
+        let parts = self.split_range(from, to);
+        let result = self.subtree(parts[1]);
+        self.join(parts);
+        result
+    }
+
+    pub fn mean(&mut self, from: i32, to: i32) -> i32 {
+        self.aggregate(from, to).mean()
+    }
+
+    // mean of the two middle prices for an even count, 0 for an empty range
+    pub fn median(&mut self, from: i32, to: i32) -> i32 {
+        let parts = self.split_range(from, to);
+        let mut entries = Vec::new();
+        self.collect(parts[1], &mut entries);
+        self.join(parts);
+        let mut prices = entries.into_iter().map(|(_, price)| price).collect::<Vec<_>>();
+        if prices.is_empty() {
+            return 0;
+        }
+        let mid = prices.len() / 2;
+        let odd = prices.len() % 2 == 1;
+        let (lower, upper, _) = prices.select_nth_unstable(mid);
+        if odd {
+            *upper
+        } else {
+            let lower = *lower.iter().max().unwrap();
+            ((lower as i64 + *upper as i64) / 2) as i32
+        }
+    }
+
+    // (timestamp, price) pairs ordered by timestamp
+    pub fn entries(&self) -> Vec<(i32, i32)> {
+        let mut entries = Vec::new();
+        self.collect(self.root, &mut entries);
+        entries
+    }
+
+    // returns the number of removed entries
+    pub fn delete(&mut self, from: i32, to: i32) -> u64 {
+        let [left, removed, right] = self.split_range(from, to);
+        let count = self.subtree(removed).count;
+        self.release(removed);
+        self.join([left, NIL, right]);
+        count
+    }
+
+    // splits into (timestamp < from, from <= timestamp <= to, timestamp > to)
+    fn split_range(&mut self, from: i32, to: i32) -> [u32; 3] {
+        let (left, rest) = self.split(self.root, from as i64);
+        let (middle, right) = self.split(rest, to as i64 + 1);
+        [left, middle, right]
+    }
+
+    fn join(&mut self, [left, middle, right]: [u32; 3]) {
+        let left = self.merge(left, middle);
+        self.root = self.merge(left, right);
+    }
+
+    fn new_node(&mut self, timestamp: i32, price: i32) -> u32 {
+        let node = Node {
+            timestamp,
+            price,
+            priority: self.next_priority(),
+            children: [NIL, NIL],
+            subtree: Aggregate::single(price),
+        };
+        if let Some(index) = self.free.pop() {
+            self.nodes[index as usize] = node;
+            index
+        } else {
+            self.nodes.push(node);
+            self.nodes.len() as u32 - 1
+        }
+    }
+
+    // puts the whole subtree on the free list
+    fn release(&mut self, node: u32) {
+        if node != NIL {
+            let [left, right] = self.nodes[node as usize].children;
+            self.release(left);
+            self.release(right);
+            self.free.push(node);
+        }
+    }
+
+    fn collect(&self, node: u32, out: &mut Vec<(i32, i32)>) {
+        if node != NIL {
+            let node = &self.nodes[node as usize];
+            self.collect(node.children[0], out);
+            out.push((node.timestamp, node.price));
+            self.collect(node.children[1], out);
+        }
+    }
+
+    fn subtree(&self, node: u32) -> Aggregate {
+        if node == NIL {
+            Aggregate::default()
+        } else {
+            self.nodes[node as usize].subtree
+        }
+    }
+
+    fn update(&mut self, node: u32) {
+        let [left, right] = self.nodes[node as usize].children;
+        let mut subtree = Aggregate::single(self.nodes[node as usize].price);
+        subtree.add(self.subtree(left));
+        subtree.add(self.subtree(right));
+        self.nodes[node as usize].subtree = subtree;
+    }
+
+    // splits into (timestamp < bound, timestamp >= bound)
+    fn split(&mut self, node: u32, bound: i64) -> (u32, u32) {
+        if node == NIL {
+            return (NIL, NIL);
+        }
+        if (self.nodes[node as usize].timestamp as i64) < bound {
+            let (left, right) = self.split(self.nodes[node as usize].children[1], bound);
+            self.nodes[node as usize].children[1] = left;
+            self.update(node);
+            (node, right)
+        } else {
+            let (left, right) = self.split(self.nodes[node as usize].children[0], bound);
+            self.nodes[node as usize].children[0] = right;
+            self.update(node);
+            (left, node)
+        }
+    }
+
+    // every timestamp in `left` must be <= every timestamp in `right`
+    fn merge(&mut self, left: u32, right: u32) -> u32 {
+        if left == NIL {
+            return right;
+        }
+        if right == NIL {
+            return left;
+        }
+        if self.nodes[left as usize].priority > self.nodes[right as usize].priority {
+            let merged = self.merge(self.nodes[left as usize].children[1], right);
+            self.nodes[left as usize].children[1] = merged;
+            self.update(left);
+            left
+        } else {
+            let merged = self.merge(left, self.nodes[right as usize].children[0]);
+            self.nodes[right as usize].children[0] = merged;
+            self.update(right);
+            right
+        }
+    }
+
+    fn next_priority(&mut self) -> u64 {
+        // xorshift64
+        self.rng ^= self.rng << 13;
+        self.rng ^= self.rng >> 7;
+        self.rng ^= self.rng << 17;
+        self.rng
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Instant;
+
+    use super::*;
+
+    // the previous approach: sort the whole session on every query
+    #[derive(Default)]
+    struct Sorted(Vec<(i32, i32)>);
+
+    impl Sorted {
+        fn insert(&mut self, timestamp: i32, price: i32) {
+            self.0.push((timestamp, price));
+        }
+
+        fn range(&mut self, from: i32, to: i32) -> &[(i32, i32)] {
+            self.0.sort();
+            let start = self.0.partition_point(|(timestamp, _)| *timestamp < from);
+            let end = self.0.partition_point(|(timestamp, _)| *timestamp <= to);
+            &self.0[start..end.max(start)]
+        }
+
+        fn mean(&mut self, from: i32, to: i32) -> i32 {
+            let range = self.range(from, to);
+            if range.is_empty() {
+                return 0;
+            }
+            let sum: i128 = range.iter().map(|(_, price)| *price as i128).sum();
+            (sum / range.len() as i128) as i32
+        }
+    }
+
+    struct Rng(u64);
+
+    impl Rng {
+        fn next(&mut self, bound: i32) -> i32 {
+            self.0 ^= self.0 << 13;
+            self.0 ^= self.0 >> 7;
+            self.0 ^= self.0 << 17;
+            (self.0 % bound as u64) as i32
+        }
+    }
+
+    #[test]
+    fn mean_matches_sorted_reference() {
+        let mut rng = Rng(42);
+        let mut prices = Prices::new(DuplicatePolicy::KeepAll);
+        let mut reference = Sorted::default();
+        for _ in 0..5000 {
+            let (timestamp, price) = (rng.next(2000) - 1000, rng.next(200_000) - 100_000);
+            prices.insert(timestamp, price).unwrap();
+            reference.insert(timestamp, price);
+            let (from, to) = (rng.next(2400) - 1200, rng.next(2400) - 1200);
+            assert_eq!(
+                prices.mean(from, to),
+                reference.mean(from, to),
+                "{}..={}",
+                from,
+                to
+            );
+        }
+        for (from, to) in [
+            (i32::MIN, i32::MAX),
+            (i32::MAX, i32::MIN),
+            (5, 5),
+            (2000, 3000),
+        ] {
+            assert_eq!(
+                prices.mean(from, to),
+                reference.mean(from, to),
+                "{}..={}",
+                from,
+                to
+            );
+        }
+    }
+
+    #[test]
+    fn aggregates_match_sorted_reference() {
+        let mut rng = Rng(7);
+        let mut prices = Prices::new(DuplicatePolicy::KeepAll);
+        let mut reference = Sorted::default();
+        for _ in 0..2000 {
+            let (timestamp, price) = (rng.next(500), rng.next(1000) - 500);
+            prices.insert(timestamp, price).unwrap();
+            reference.insert(timestamp, price);
+        }
+        for _ in 0..500 {
+            let (from, to) = (rng.next(600), rng.next(600));
+            let aggregate = prices.aggregate(from, to);
+            let range = reference.range(from, to).to_vec();
+            assert_eq!(aggregate.count, range.len() as u64);
+            assert_eq!(
+                aggregate.sum,
+                range.iter().map(|(_, p)| *p as i64).sum::<i64>()
+            );
+            assert_eq!(
+                aggregate.min(),
+                range.iter().map(|(_, p)| *p).min().unwrap_or(0)
+            );
+            assert_eq!(
+                aggregate.max(),
+                range.iter().map(|(_, p)| *p).max().unwrap_or(0)
+            );
+            let mut sorted = range.iter().map(|(_, p)| *p as i64).collect::<Vec<_>>();
+            sorted.sort();
+            let median = match sorted.len() {
+                0 => 0,
+                n if n % 2 == 1 => sorted[n / 2],
+                n => (sorted[n / 2 - 1] + sorted[n / 2]) / 2,
+            };
+            assert_eq!(prices.median(from, to) as i64, median);
+        }
+        // entries are ordered by timestamp, prices of equal timestamps in insertion order
+        let mut entries = prices.entries();
+        entries.sort();
+        assert_eq!(entries, reference.range(i32::MIN, i32::MAX));
+    }
+
+    #[test]
+    fn delete_removes_range() {
+        let mut prices = Prices::new(DuplicatePolicy::KeepAll);
+        for timestamp in 0..100 {
+            prices.insert(timestamp, timestamp * 10).unwrap();
+        }
+        assert_eq!(prices.delete(10, 19), 10);
+        assert_eq!(prices.delete(10, 19), 0);
+        assert_eq!(prices.aggregate(0, 99).count, 90);
+        assert_eq!(
+            prices.mean(5, 24),
+            (50 + 60 + 70 + 80 + 90 + 200 + 210 + 220 + 230 + 240) / 10
+        );
+        // freed nodes are reused
+        prices.insert(15, 1).unwrap();
+        assert_eq!(prices.nodes.len(), 100);
+    }
+
+    fn with_duplicate(policy: DuplicatePolicy) -> (Prices, Vec<bool>) {
+        let mut prices = Prices::new(policy);
+        let accepted = [(10, 100), (20, 50), (10, 300)]
+            .into_iter()
+            .map(|(timestamp, price)| prices.insert(timestamp, price).is_ok())
+            .collect();
+        (prices, accepted)
+    }
+
+    #[test]
+    fn keep_all_averages_duplicates() {
+        let (mut prices, accepted) = with_duplicate(DuplicatePolicy::KeepAll);
+        assert_eq!(accepted, [true, true, true]);
+        assert_eq!(prices.mean(10, 10), 200);
+        assert_eq!(prices.mean(0, 100), 150);
+        assert_eq!(prices.aggregate(0, 100).count, 3);
+    }
+
+    #[test]
+    fn first_wins_ignores_later_duplicates() {
+        let (mut prices, accepted) = with_duplicate(DuplicatePolicy::FirstWins);
+        assert_eq!(accepted, [true, true, true]);
+        assert_eq!(prices.mean(10, 10), 100);
+        assert_eq!(prices.mean(0, 100), 75);
+        assert_eq!(prices.aggregate(0, 100).count, 2);
+    }
+
+    #[test]
+    fn last_wins_replaces_earlier_price() {
+        let (mut prices, accepted) = with_duplicate(DuplicatePolicy::LastWins);
+        assert_eq!(accepted, [true, true, true]);
+        assert_eq!(prices.mean(10, 10), 300);
+        assert_eq!(prices.mean(0, 100), 175);
+        assert_eq!(prices.aggregate(0, 100).count, 2);
+    }
+
+    #[test]
+    fn reject_refuses_duplicates() {
+        let (mut prices, accepted) = with_duplicate(DuplicatePolicy::Reject);
+        assert_eq!(accepted, [true, true, false]);
+        assert_eq!(prices.mean(10, 10), 100);
+        assert_eq!(prices.mean(0, 100), 75);
+        assert_eq!(prices.aggregate(0, 100).count, 2);
+    }
+
+    // cargo test --release benchmark -- --ignored --nocapture
+    #[test]
+    #[ignore]
+    fn benchmark_200k_session() {
+        const MESSAGES: usize = 200_000;
+        let mut rng = Rng(1);
+        let messages = (0..MESSAGES)
+            .map(|i| {
+                let insert = i % 2 == 0;
+                (insert, rng.next(1_000_000), rng.next(1_000_000))
+            })
+            .collect::<Vec<_>>();
+
+        let start = Instant::now();
+        let mut prices = Prices::new(DuplicatePolicy::KeepAll);
+        let mut treap_checksum = 0_i64;
+        for (insert, a, b) in &messages {
+            if *insert {
+                prices.insert(*a, *b).unwrap();
+            } else {
+                treap_checksum += prices.mean(*a.min(b), *a.max(b)) as i64;
+            }
+        }
+        let treap = start.elapsed();
+
+        let start = Instant::now();
+        let mut reference = Sorted::default();
+        let mut sorted_checksum = 0_i64;
+        for (insert, a, b) in &messages {
+            if *insert {
+                reference.insert(*a, *b);
+            } else {
+                sorted_checksum += reference.mean(*a.min(b), *a.max(b)) as i64;
+            }
+        }
+        let sorted = start.elapsed();
+
+        assert_eq!(treap_checksum, sorted_checksum);
+        println!(
+            "{} messages: treap {:?}, sort per query {:?}",
+            MESSAGES, treap, sorted
+        );
+    }
+}
@@ -0,0 +1,108 @@
+use std::{
+    io::{BufRead, BufReader, Write},
+    net::{TcpListener, TcpStream},
+    sync::{Arc, Mutex},
+    time::{Duration, Instant, SystemTime},
+};
+
+use super::Chat;
+
+// Line-based operator interface. Every command gets a single `OK ...` or `ERR ...` reply line,
+// `list` sends a `<name> <room> <peer address> <seconds connected>` line per member before it.
+//   `list`, `kick <name> [reason]`, `mute <name> <seconds>`, `unmute <name>`, `announce <text>`
+pub fn serve(address: &str, chat: Arc<Mutex<Chat>>) {
+    let listener = TcpListener::bind(address).unwrap();
+    for incoming in listener.into_incoming() {
+        let stream = match incoming {
+            Ok(stream) => stream,
+            Err(e) => {
+                eprintln!("Error accepting incoming admin stream: {:?}", e);
+                continue;
+            }
+        };
+        let chat = Arc::clone(&chat);
+        std::thread::spawn(move || handle(stream, chat));
+    }
+}
+
+fn handle(mut stream: TcpStream, chat: Arc<Mutex<Chat>>) {
+    let mut buffer = BufReader::new(stream.try_clone().unwrap());
+    loop {
+        let mut line = String::new();
+        match buffer.read_line(&mut line) {
+            Ok(0) => break,
+            Err(e) => {
+                eprintln!("Error reading from admin stream: {:?}", e);
+                break;
+            }
+            Ok(_) => {}
+        }
+        let line = line.trim_end();
+        eprintln!("Admin command: {:?}", line);
+        let reply = command(&mut chat.lock().unwrap(), line);
+        if let Err(e) = stream.write_all(reply.as_bytes()) {
+            eprintln!("Error writing to admin stream: {:?}", e);
+            break;
+        }
+    }
+}
+
+fn command(chat: &mut Chat, line: &str) -> String {
+    let (command, args) = line.split_once(' ').unwrap_or((line, ""));
+    match (command, args) {
+        ("list", "") => {
+            let mut reply = String::new();
+            for member in &chat.members {
+                let connected = SystemTime::now()
+                    .duration_since(member.connected)
+                    .unwrap_or_default();
+                reply.push_str(&format!(
+                    "{} {} {} {}\n",
+                    member.name,
+                    member.room,
+                    member
+                        .peer
+                        .map_or_else(|| "unknown".to_owned(), |peer| peer.to_string()),
+                    connected.as_secs()
+                ));
+            }
+            reply.push_str(&format!("OK {}\n", chat.members.len()));
+            reply
+        }
+        ("kick", args) if !args.is_empty() => {
+            let (name, reason) = args.split_once(' ').unwrap_or((args, "by an operator"));
+            let Some(id) = chat.find(name) else { return "ERR no such member\n".to_owned(); };
+            chat.kick(id, reason);
+            "OK\n".to_owned()
+        }
+        ("mute", args) => {
+            let Some((name, seconds)) = args.split_once(' ') else {
+                return "ERR usage: mute <name> <seconds>\n".to_owned();
+            };
+            let Ok(seconds) = seconds.parse() else { return "ERR invalid duration\n".to_owned(); };
+            let Some(until) = Instant::now().checked_add(Duration::from_secs(seconds)) else {
+                return "ERR invalid duration\n".to_owned();
+            };
+            let Some(id) = chat.find(name) else { return "ERR no such member\n".to_owned(); };
+            let member = chat.member(id);
+            member.muted_until = Some(until);
+            member.send(&format!("* You have been muted for {}s\n", seconds));
+            let event = format!("mute {} {}", member.name, seconds);
+            chat.log(&event);
+            "OK\n".to_owned()
+        }
+        ("unmute", name) => {
+            let Some(id) = chat.find(name) else { return "ERR no such member\n".to_owned(); };
+            let member = chat.member(id);
+            member.muted_until = None;
+            let event = format!("unmute {}", member.name);
+            chat.log(&event);
+            "OK\n".to_owned()
+        }
+        ("announce", text) if !text.is_empty() => {
+            chat.announce(text);
+            "OK\n".to_owned()
+        }
+        _ => format!("ERR unknown command or arguments: {}\n", command),
+    }
+}
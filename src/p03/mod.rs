@@ -1,117 +1,740 @@
+mod admin;
+mod moderation;
+mod transcript;
+mod websocket;
+
 use std::{
-    io::{BufRead, BufReader, Write},
-    net::{TcpListener, TcpStream},
-    sync::{Arc, Mutex},
+    collections::{BTreeSet, HashMap, VecDeque},
+    fs::File,
+    io::{BufRead, BufReader, Read, Write},
+    net::{SocketAddr, TcpListener, TcpStream},
+    sync::{Arc, Condvar, Mutex},
+    time::{Duration, Instant, SystemTime},
 };
 
-pub fn main() {
-    let listener = TcpListener::bind("0.0.0.0:1200").unwrap();
-    let members: Arc<Mutex<Vec<(String, TcpStream)>>> = Default::default();
-    for incoming in listener.into_incoming() {
-        let mut stream = match incoming {
-            Ok(stream) => stream,
-            Err(e) => {
-                eprintln!("Error accepting incoming stream: {:?}", e);
-                continue;
-            }
-        };
-        let members = Arc::clone(&members);
-        std::thread::spawn(move || {
-            let mut buffer = BufReader::new(stream.try_clone().unwrap());
-            stream.write_all(b"Welcome. What's your name?\n").unwrap();
-            let mut msg = String::new();
-            buffer.read_line(&mut msg).unwrap();
-            let name = msg.trim_end().to_owned();
-            if name.len() < 1
-                || !name.chars().all(|c| c.is_alphanumeric())
-                || members
-                    .lock()
-                    .unwrap()
-                    .iter()
-                    .any(|(name2, _)| *name2 == name)
-            {
-                stream.write_all(b"Invalid name\n").unwrap();
-                stream.shutdown(std::net::Shutdown::Both).unwrap();
-                return;
-            }
+use moderation::{FilterAction, Moderator, Verdict, WordFilter};
+use transcript::Transcript;
 
-            {
-                let mut unlocked_members = members.lock().unwrap();
-                let mut names = b"* Connected users:".to_vec();
-                for (name, _) in &*unlocked_members {
-                    names.push(b' ');
-                    names.extend_from_slice(name.as_bytes());
+// Opt-in slash commands on top of the standard protocol. Plain lines are always broadcast.
+//   `/join #room` moves to a room, `/leave` goes back to the default room, `/rooms` lists rooms,
+//   `/msg <name> <text>` sends a private message, `/who` lists the room, `/me <action>`,
+//   `/nick <name>` renames, `/quit` disconnects
+// Errors are only reported back to the sender.
+const EXTENDED: bool = false;
+// Everyone starts here, so clients unaware of rooms see the standard single-room behavior
+const DEFAULT_ROOM: &str = "#general";
+// Lines are written to each member by a dedicated thread, so that a slow reader doesn't block
+// everyone else. This is what happens when one falls this many lines behind.
+const MAX_QUEUED_LINES: usize = 1024;
+const OVERFLOW_POLICY: OverflowPolicy = OverflowPolicy::Disconnect;
+// Name rules, applied both when joining and on `/nick`. Lengths are in characters, the standard
+// protocol sets no maximum.
+const MIN_NAME_LENGTH: usize = 1;
+const MAX_NAME_LENGTH: usize = usize::MAX;
+// `char::is_alphanumeric` admits letters and digits of any script
+const ASCII_ONLY_NAMES: bool = false;
+const CASE_INSENSITIVE_NAMES: bool = false;
+const RESERVED_NAMES: &[&str] = &[];
+// Number of past room events (messages and presence changes) replayed to members entering a room,
+// after the presence line. 0 disables history, as the standard protocol has none.
+const HISTORY_LENGTH: usize = 0;
+// History is appended to this file and loaded from it on startup, when set
+const HISTORY_FILE: Option<&str> = None;
+// Flood protection, none of it enabled by default. Every line (message or command) takes a token
+// from a bucket holding up to `burst` tokens and refilled at `per_second`.
+const RATE_LIMIT: Option<RateLimit> = None;
+// Lines longer than this many characters are truncated or rejected, per OVERLONG_POLICY. Lines
+// that don't even fit in 4 bytes per character are never read into memory as a whole, and are
+// rejected either way.
+const MAX_MESSAGE_LENGTH: Option<usize> = None;
+const OVERLONG_POLICY: OverlongPolicy = OverlongPolicy::Reject;
+// Rejected lines count as violations, this many of them within VIOLATION_WINDOW get the member
+// kicked
+const KICK_AFTER_VIOLATIONS: usize = 10;
+const VIOLATION_WINDOW: Duration = Duration::from_secs(60);
+// Audit trail of joins, leaves and messages, rotated once it grows over TRANSCRIPT_MAX_BYTES
+const TRANSCRIPT_FILE: Option<&str> = None;
+const TRANSCRIPT_MAX_BYTES: u64 = 16 << 20;
+const TRANSCRIPT_KEEP: usize = 5;
+// Messages containing any of these words are masked, dropped or flagged, per FILTER_ACTION
+const FILTERED_WORDS: &[&str] = &[];
+const FILTER_ACTION: FilterAction = FilterAction::Mask;
+// Port of a WebSocket listener joining the same chat. Each text frame is a line from the client,
+// and each line to the client is sent as a text frame.
+const WEBSOCKET_PORT: Option<u16> = None;
+// Address of the operator interface (see admin.rs). It has no authentication of its own, so it
+// should only be reachable by operators.
+const ADMIN_ADDRESS: Option<&str> = None;
+
+struct RateLimit {
+    per_second: f64,
+    burst: f64,
+}
+
+#[allow(dead_code)]
+#[derive(Clone, Copy)]
+enum OverlongPolicy {
+    Truncate,
+    Reject,
+}
+
+struct TokenBucket {
+    tokens: f64,
+    refilled: Instant,
+}
+
+impl TokenBucket {
+    fn new() -> Self {
+        Self {
+            tokens: RATE_LIMIT.as_ref().map_or(0.0, |limit| limit.burst),
+            refilled: Instant::now(),
+        }
+    }
+
+    fn take(&mut self) -> bool {
+        let Some(limit) = &RATE_LIMIT else { return true; };
+        let now = Instant::now();
+        let elapsed = (now - self.refilled).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * limit.per_second).min(limit.burst);
+        self.refilled = now;
+        if self.tokens >= 1.0 {
+            self.tokens -= 1.0;
+            true
+        } else {
+            false
+        }
+    }
+}
+
+#[allow(dead_code)]
+#[derive(Clone, Copy)]
+enum OverflowPolicy {
+    DropOldest,
+    Disconnect,
+}
+
+// pending lines, and whether the writer should stop once they are written
+#[derive(Default)]
+struct Outbox {
+    state: Mutex<(VecDeque<String>, bool)>,
+    waker: Condvar,
+}
+
+impl Outbox {
+    fn close(&self) {
+        self.state.lock().unwrap().1 = true;
+        self.waker.notify_all();
+    }
+}
+
+struct Member {
+    id: u64,
+    name: String,
+    room: String,
+    stream: TcpStream,
+    outbox: Arc<Outbox>,
+    peer: Option<SocketAddr>,
+    connected: SystemTime,
+    muted_until: Option<Instant>,
+    // set by `kick`, the departure has already been announced
+    kicked: bool,
+}
+
+impl Member {
+    fn send(&mut self, line: &str) {
+        let mut state = self.outbox.state.lock().unwrap();
+        let (queue, closed) = &mut *state;
+        if *closed {
+            return;
+        }
+        if queue.len() >= MAX_QUEUED_LINES {
+            match OVERFLOW_POLICY {
+                OverflowPolicy::DropOldest => {
+                    eprintln!("Outbox of {} full, dropping oldest line", self.name);
+                    queue.pop_front();
                 }
-                names.push(b'\n');
-                if let Err(e) = stream.write_all(&names) {
-                    eprintln!("Error writing hello to stream {}: {:?}", name, e);
-                    stream.shutdown(std::net::Shutdown::Both).unwrap();
+                OverflowPolicy::Disconnect => {
+                    eprintln!("Outbox of {} full, disconnecting", self.name);
+                    queue.clear();
+                    *closed = true;
+                    let _ = self.stream.shutdown(std::net::Shutdown::Both);
                     return;
                 }
-                for (name2, stream) in &mut *unlocked_members {
-                    let res: Result<(), std::io::Error> = try {
-                        stream.write_all(b"* New chat member: ")?;
-                        stream.write_all(name.as_bytes())?;
-                        stream.write_all(b"\n")?;
-                    };
-                    if let Err(e) = res {
-                        eprintln!("Error writing member intro to stream {}: {:?}", name2, e);
-                        stream.shutdown(std::net::Shutdown::Both).unwrap();
+            }
+        }
+        queue.push_back(line.to_owned());
+        self.outbox.waker.notify_one();
+    }
+}
+
+// lets the writer finish the lines that are already queued
+impl Drop for Member {
+    fn drop(&mut self) {
+        self.outbox.close();
+    }
+}
+
+// `stream` is the underlying connection of `output`, shut down when writing fails
+fn writer(mut output: impl Write, stream: TcpStream, outbox: Arc<Outbox>, id: u64) {
+    loop {
+        let mut state = outbox.state.lock().unwrap();
+        let line = loop {
+            if let Some(line) = state.0.pop_front() {
+                break line;
+            }
+            if state.1 {
+                return;
+            }
+            state = outbox.waker.wait(state).unwrap();
+        };
+        drop(state);
+        if let Err(e) = output.write_all(line.as_bytes()) {
+            eprintln!("Error writing to stream of member {}: {:?}", id, e);
+            let _ = stream.shutdown(std::net::Shutdown::Both);
+            outbox.close();
+            return;
+        }
+    }
+}
+
+#[derive(Default)]
+struct Chat {
+    members: Vec<Member>,
+    next_id: u64,
+    history: HashMap<String, VecDeque<String>>,
+    history_file: Option<File>,
+    transcript: Option<Transcript>,
+    moderators: Vec<Box<dyn Moderator>>,
+}
+
+fn valid_name(name: &str) -> bool {
+    let length = name.chars().count();
+    (MIN_NAME_LENGTH..=MAX_NAME_LENGTH).contains(&length)
+        && name.chars().all(|c| {
+            if ASCII_ONLY_NAMES {
+                c.is_ascii_alphanumeric()
+            } else {
+                c.is_alphanumeric()
+            }
+        })
+        && !RESERVED_NAMES
+            .iter()
+            .any(|reserved| same_name(reserved, name))
+}
+
+fn same_name(a: &str, b: &str) -> bool {
+    if CASE_INSENSITIVE_NAMES {
+        a.to_lowercase() == b.to_lowercase()
+    } else {
+        a == b
+    }
+}
+
+impl Chat {
+    // history file lines are `<room> <line>`, the file is compacted to the kept lines on load
+    fn load_history(&mut self) {
+        let Some(path) = HISTORY_FILE else { return; };
+        match std::fs::read_to_string(path) {
+            Ok(contents) => {
+                for line in contents.lines() {
+                    if let Some((room, line)) = line.split_once(' ') {
+                        self.record(room, &format!("{}\n", line));
                     }
                 }
-                unlocked_members.push((name.clone(), stream.try_clone().unwrap()));
-            }
-            loop {
-                let mut msg = String::new();
-                let res = buffer.read_line(&mut msg);
-                if let Err(e) = res {
-                    eprintln!(
-                        "Error reading from stream {}: {:?}. Read buffer contents: {:?}",
-                        name, e, msg
-                    );
-                    let _ = stream.shutdown(std::net::Shutdown::Both);
-                    break;
+            }
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => {}
+            Err(e) => eprintln!("Error reading history file {}: {:?}", path, e),
+        }
+        let res: Result<File, std::io::Error> = try {
+            let mut file = File::create(path)?;
+            for (room, lines) in &self.history {
+                for line in lines {
+                    write!(file, "{} {}", room, line)?;
                 }
-                if matches!(res, Ok(0)) {
-                    eprintln!("Stream {} closed by peer", name);
+            }
+            file
+        };
+        match res {
+            Ok(file) => self.history_file = Some(file),
+            Err(e) => eprintln!("Error writing history file {}: {:?}", path, e),
+        }
+    }
+
+    fn record(&mut self, room: &str, line: &str) {
+        if HISTORY_LENGTH == 0 {
+            return;
+        }
+        let history = self.history.entry(room.to_owned()).or_default();
+        history.push_back(line.to_owned());
+        if history.len() > HISTORY_LENGTH {
+            history.pop_front();
+        }
+        if let Some(file) = &mut self.history_file
+            && let Err(e) = write!(file, "{} {}", room, line)
+        {
+            eprintln!("Error appending to history file: {:?}", e);
+            self.history_file = None;
+        }
+    }
+
+    fn log(&mut self, event: &str) {
+        if let Some(transcript) = &mut self.transcript {
+            transcript.record(event);
+        }
+    }
+
+    // returns the text to send, if any
+    fn moderate(&mut self, id: u64, text: &str) -> Option<String> {
+        let member = self.member(id);
+        if let Some(until) = member.muted_until
+            && let Some(remaining) = until.checked_duration_since(Instant::now())
+        {
+            let line = format!("* You are muted for {}s\n", remaining.as_secs() + 1);
+            member.send(&line);
+            return None;
+        }
+        let (name, room) = (member.name.clone(), member.room.clone());
+        let mut text = text.to_owned();
+        let mut moderators = std::mem::take(&mut self.moderators);
+        let mut dropped = false;
+        for moderator in &mut moderators {
+            match moderator.moderate(&name, &room, &text) {
+                Verdict::Pass => {}
+                Verdict::Rewrite(new_text) => text = new_text,
+                Verdict::Drop(reason) => {
+                    eprintln!("Dropped message from {}: {}: {:?}", name, reason, text);
+                    self.log(&format!("drop {} {} {}: {}", room, name, reason, text));
+                    self.member(id)
+                        .send(&format!("* Message dropped: {}\n", reason));
+                    dropped = true;
                     break;
                 }
-                let msg = msg.trim_end().to_owned();
-                {
-                    let mut unlocked_members = members.lock().unwrap();
-                    for (name2, stream) in unlocked_members.iter_mut() {
-                        if name == *name2 {
-                            continue;
-                        }
-                        let res: Result<(), std::io::Error> = try {
-                            stream.write_all(b"[")?;
-                            stream.write_all(name.as_bytes())?;
-                            stream.write_all(b"] ")?;
-                            stream.write_all(msg.as_bytes())?;
-                            stream.write_all(b"\n")?;
-                        };
-                        if let Err(e) = res {
-                            eprintln!("Error writing message to stream {}: {:?}", name2, e);
-                            let _ = stream.shutdown(std::net::Shutdown::Both);
-                        }
-                    }
+                Verdict::Flag(reason) => {
+                    eprintln!("Flagged message from {}: {}: {:?}", name, reason, text);
+                    self.log(&format!("flag {} {} {}: {}", room, name, reason, text));
                 }
             }
+        }
+        self.moderators = moderators;
+        if dropped {
+            None
+        } else {
+            Some(text)
+        }
+    }
+
+    fn member(&mut self, id: u64) -> &mut Member {
+        self.members.iter_mut().find(|m| m.id == id).unwrap()
+    }
+
+    fn find(&self, name: &str) -> Option<u64> {
+        self.members
+            .iter()
+            .find(|m| same_name(&m.name, name))
+            .map(|m| m.id)
+    }
+
+    fn name_taken(&self, name: &str, except: Option<u64>) -> bool {
+        self.members
+            .iter()
+            .any(|m| Some(m.id) != except && same_name(&m.name, name))
+    }
+
+    // everything broadcast to a room is also recorded in its history
+    fn broadcast(&mut self, room: &str, except: u64, line: &str) {
+        for member in &mut self.members {
+            if member.room == room && member.id != except {
+                member.send(line);
+            }
+        }
+        self.record(room, line);
+    }
+
+    // sends the presence line to the member and announces them to everyone else in the room
+    fn enter(&mut self, id: u64, room: &str) {
+        let mut names = "* Connected users:".to_owned();
+        for member in &self.members {
+            if member.room == room && member.id != id {
+                names.push(' ');
+                names.push_str(&member.name);
+            }
+        }
+        names.push('\n');
+        let history = self.history.get(room).cloned().unwrap_or_default();
+        let member = self.member(id);
+        member.room = room.to_owned();
+        member.send(&names);
+        for line in history {
+            member.send(&line);
+        }
+        let line = format!("* New chat member: {}\n", member.name);
+        let event = format!("join {} {}", room, member.name);
+        self.broadcast(room, id, &line);
+        self.log(&event);
+    }
+
+    fn exit(&mut self, id: u64) {
+        let member = self.member(id);
+        let room = member.room.clone();
+        let line = format!("* {} is no longer among us\n", member.name);
+        let event = format!("leave {} {}", room, member.name);
+        if !member.kicked {
+            self.broadcast(&room, id, &line);
+        }
+        self.log(&event);
+    }
+
+    // the member's reader thread sees EOF and leaves the chat as usual
+    fn kick(&mut self, id: u64, reason: &str) {
+        let member = self.member(id);
+        if member.kicked {
+            return;
+        }
+        member.kicked = true;
+        member.send(&format!("* You have been kicked: {}\n", reason));
+        let _ = member.stream.shutdown(std::net::Shutdown::Read);
+        let room = member.room.clone();
+        let line = format!("* {} has been kicked: {}\n", member.name, reason);
+        let event = format!("kick {} {} {}", room, member.name, reason);
+        self.broadcast(&room, id, &line);
+        self.log(&event);
+    }
+
+    // to every member, in every room
+    fn announce(&mut self, text: &str) {
+        let line = format!("* Announcement: {}\n", text);
+        for member in &mut self.members {
+            member.send(&line);
+        }
+        self.log(&format!("announce {}", text));
+    }
+
+    fn message(&mut self, id: u64, msg: &str) {
+        let Some(msg) = self.moderate(id, msg) else { return; };
+        let member = self.member(id);
+        let room = member.room.clone();
+        let line = format!("[{}] {}\n", member.name, msg);
+        let event = format!("message {} {} {}", room, member.name, msg);
+        self.broadcast(&room, id, &line);
+        self.log(&event);
+    }
+
+    // returns false when the member quits
+    fn command(&mut self, id: u64, command: &str) -> bool {
+        let name = self.member(id).name.clone();
+        let room = self.member(id).room.clone();
+        let (command, args) = command.split_once(' ').unwrap_or((command, ""));
+        match (command, args) {
+            ("join", room)
+                if room.len() > 1
+                    && room.starts_with('#')
+                    && room[1..].chars().all(|c| c.is_alphanumeric()) =>
             {
-                let mut unlocked_members = members.lock().unwrap();
-                unlocked_members.retain(|(n, _)| n != &name);
-                for (name2, stream) in &mut *unlocked_members {
-                    let res: Result<(), std::io::Error> = try {
-                        stream.write_all(b"* ")?;
-                        stream.write_all(name.as_bytes())?;
-                        stream.write_all(b" is no longer among us\n")?;
-                    };
-                    if let Err(e) = res {
-                        eprintln!("Error writing close message to stream {}: {:?}", name2, e);
-                        let _ = stream.shutdown(std::net::Shutdown::Both);
+                if self.member(id).room == room {
+                    self.member(id).send(&format!("* Already in {}\n", room));
+                } else {
+                    self.exit(id);
+                    self.enter(id, room);
+                }
+            }
+            ("join", _) => self.member(id).send("* Usage: /join #room\n"),
+            ("leave", "") => {
+                if room == DEFAULT_ROOM {
+                    self.member(id).send("* Not in a room\n");
+                } else {
+                    self.exit(id);
+                    self.enter(id, DEFAULT_ROOM);
+                }
+            }
+            ("rooms", "") => {
+                let rooms = std::iter::once(DEFAULT_ROOM)
+                    .chain(self.members.iter().map(|m| m.room.as_str()))
+                    .collect::<BTreeSet<_>>();
+                let mut line = "* Rooms:".to_owned();
+                for room in rooms {
+                    line.push(' ');
+                    line.push_str(room);
+                }
+                line.push('\n');
+                self.member(id).send(&line);
+            }
+            ("msg", args) => match args.split_once(' ') {
+                Some((to, text)) => {
+                    let Some(text) = self.moderate(id, text) else { return true; };
+                    let line = format!("[{} -> {}] {}\n", name, to, text);
+                    match self.members.iter_mut().find(|m| same_name(&m.name, to)) {
+                        Some(recipient) => {
+                            recipient.send(&line);
+                            let event = format!("private {} {} {}", name, recipient.name, text);
+                            self.log(&event);
+                        }
+                        None => self.member(id).send(&format!("* No such member: {}\n", to)),
                     }
                 }
+                None => self.member(id).send("* Usage: /msg <name> <text>\n"),
+            },
+            ("who", "") => {
+                let mut line = format!("* Members of {}:", room);
+                for member in &self.members {
+                    if member.room == room {
+                        line.push(' ');
+                        line.push_str(&member.name);
+                    }
+                }
+                line.push('\n');
+                self.member(id).send(&line);
+            }
+            ("me", action) if !action.is_empty() => {
+                let Some(action) = self.moderate(id, action) else { return true; };
+                self.broadcast(&room, id, &format!("* {} {}\n", name, action));
+                self.log(&format!("action {} {} {}", room, name, action));
+            }
+            ("nick", new_name) if valid_name(new_name) => {
+                if self.name_taken(new_name, Some(id)) {
+                    self.member(id)
+                        .send(&format!("* Name {} is taken\n", new_name));
+                } else {
+                    self.member(id).name = new_name.to_owned();
+                    let line = format!("* {} is now known as {}\n", name, new_name);
+                    self.broadcast(&room, id, &line);
+                    self.log(&format!("nick {} {} {}", room, name, new_name));
+                }
+            }
+            ("nick", _) => self.member(id).send("* Usage: /nick <alphanumeric name>\n"),
+            ("quit", "") => return false,
+            _ => self
+                .member(id)
+                .send(&format!("* Unknown command or arguments: /{}\n", command)),
+        }
+        true
+    }
+}
+
+pub fn main() {
+    let listener = TcpListener::bind("0.0.0.0:1200").unwrap();
+    let chat: Arc<Mutex<Chat>> = Default::default();
+    {
+        let mut chat = chat.lock().unwrap();
+        chat.load_history();
+        chat.transcript = TRANSCRIPT_FILE
+            .map(|path| Transcript::new(path, TRANSCRIPT_MAX_BYTES, TRANSCRIPT_KEEP));
+        if !FILTERED_WORDS.is_empty() {
+            chat.moderators
+                .push(Box::new(WordFilter::new(FILTERED_WORDS, FILTER_ACTION)));
+        }
+    }
+    if let Some(address) = ADMIN_ADDRESS {
+        let chat = Arc::clone(&chat);
+        std::thread::spawn(move || admin::serve(address, chat));
+    }
+    if let Some(port) = WEBSOCKET_PORT {
+        let listener = TcpListener::bind(("0.0.0.0", port)).unwrap();
+        let chat = Arc::clone(&chat);
+        std::thread::spawn(move || {
+            for incoming in listener.into_incoming() {
+                let stream = match incoming {
+                    Ok(stream) => stream,
+                    Err(e) => {
+                        eprintln!("Error accepting incoming websocket: {:?}", e);
+                        continue;
+                    }
+                };
+                let chat = Arc::clone(&chat);
+                std::thread::spawn(
+                    move || match websocket::accept(stream.try_clone().unwrap()) {
+                        Ok((reader, writer)) => {
+                            handle(chat, stream, BufReader::new(reader), writer)
+                        }
+                        Err(e) => eprintln!("Error in websocket handshake: {:?}", e),
+                    },
+                );
+            }
+        });
+    }
+    for incoming in listener.into_incoming() {
+        let stream = match incoming {
+            Ok(stream) => stream,
+            Err(e) => {
+                eprintln!("Error accepting incoming stream: {:?}", e);
+                continue;
             }
+        };
+        let chat = Arc::clone(&chat);
+        std::thread::spawn(move || {
+            let buffer = BufReader::new(stream.try_clone().unwrap());
+            let output = stream.try_clone().unwrap();
+            handle(chat, stream, buffer, output);
+        });
+    }
+}
+
+// like `read_line`, but a line of `limit` bytes or more is discarded up to its end instead of
+// being stored, in which case the second value is true
+fn read_line_bounded(
+    buffer: &mut impl BufRead,
+    line: &mut String,
+    limit: usize,
+) -> std::io::Result<(usize, bool)> {
+    let mut bytes = Vec::new();
+    let size = Read::take(&mut *buffer, limit as u64).read_until(b'\n', &mut bytes)?;
+    if size == limit && !bytes.ends_with(b"\n") {
+        return Ok((size + buffer.skip_until(b'\n')?, true));
+    }
+    let text = std::str::from_utf8(&bytes)
+        .map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidData, e))?;
+    line.push_str(text);
+    Ok((size, false))
+}
+
+// `buffer` and `output` read and write lines over `stream`
+fn handle(
+    chat: Arc<Mutex<Chat>>,
+    stream: TcpStream,
+    mut buffer: impl BufRead,
+    mut output: impl Write + Send + 'static,
+) {
+    output.write_all(b"Welcome. What's your name?\n").unwrap();
+    // handed over to the writer thread once the member joins
+    let mut output = Some(output);
+    let mut msg = String::new();
+    buffer.read_line(&mut msg).unwrap();
+    let name = msg.trim_end().to_owned();
+    // checking and inserting under the same lock, so that two clients can't take one name
+    let id = 'join: {
+        let mut chat = chat.lock().unwrap();
+        if !valid_name(&name) || chat.name_taken(&name, None) {
+            break 'join None;
+        }
+        let id = chat.next_id;
+        chat.next_id += 1;
+        let outbox: Arc<Outbox> = Default::default();
+        let writer_stream = stream.try_clone().unwrap();
+        let writer_outbox = Arc::clone(&outbox);
+        let output = output.take().unwrap();
+        std::thread::spawn(move || writer(output, writer_stream, writer_outbox, id));
+        chat.members.push(Member {
+            id,
+            name: name.clone(),
+            room: DEFAULT_ROOM.to_owned(),
+            stream: stream.try_clone().unwrap(),
+            outbox,
+            peer: stream.peer_addr().ok(),
+            connected: SystemTime::now(),
+            muted_until: None,
+            kicked: false,
         });
+        chat.enter(id, DEFAULT_ROOM);
+        Some(id)
+    };
+    let Some(id) = id else {
+        output.unwrap().write_all(b"Invalid name\n").unwrap();
+        stream.shutdown(std::net::Shutdown::Both).unwrap();
+        return;
+    };
+    let mut bucket = TokenBucket::new();
+    let mut violations = VecDeque::new();
+    loop {
+        let mut msg = String::new();
+        let res = match MAX_MESSAGE_LENGTH {
+            // the longest a character can be, plus a `\r\n`
+            Some(max) => read_line_bounded(
+                &mut buffer,
+                &mut msg,
+                max.saturating_mul(4).saturating_add(2),
+            ),
+            None => buffer.read_line(&mut msg).map(|size| (size, false)),
+        };
+        if let Err(e) = res {
+            eprintln!(
+                "Error reading from stream {}: {:?}. Read buffer contents: {:?}",
+                name, e, msg
+            );
+            let _ = stream.shutdown(std::net::Shutdown::Both);
+            break;
+        }
+        if matches!(res, Ok((0, _))) {
+            eprintln!("Stream {} closed by peer", name);
+            break;
+        }
+        let cut = matches!(res, Ok((_, true)));
+        let mut msg = msg.trim_end().to_owned();
+        let rejection = 'check: {
+            if !bucket.take() {
+                break 'check Some("* Slow down\n");
+            }
+            if cut {
+                break 'check Some("* Message too long\n");
+            }
+            if let Some(max) = MAX_MESSAGE_LENGTH
+                && msg.chars().count() > max
+            {
+                match OVERLONG_POLICY {
+                    OverlongPolicy::Truncate => msg = msg.chars().take(max).collect(),
+                    OverlongPolicy::Reject => break 'check Some("* Message too long\n"),
+                }
+            }
+            None
+        };
+        let mut chat = chat.lock().unwrap();
+        // lines still buffered when an operator kicked the member are ignored
+        if chat.member(id).kicked {
+            break;
+        }
+        if let Some(rejection) = rejection {
+            let now = Instant::now();
+            while violations
+                .front()
+                .is_some_and(|at| now.duration_since(*at) >= VIOLATION_WINDOW)
+            {
+                violations.pop_front();
+            }
+            violations.push_back(now);
+            if violations.len() >= KICK_AFTER_VIOLATIONS {
+                chat.kick(id, "flooding");
+                break;
+            }
+            chat.member(id).send(rejection);
+        } else if EXTENDED && let Some(command) = msg.strip_prefix('/') {
+            if !chat.command(id, command) {
+                break;
+            }
+        } else {
+            chat.message(id, &msg);
+        }
+    }
+    {
+        let mut chat = chat.lock().unwrap();
+        chat.exit(id);
+        chat.members.retain(|m| m.id != id);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn bounded_lines() {
+        let mut buffer = &b"short\nexactly9\nway too long\nnext\nlast"[..];
+        let mut read = || {
+            let mut line = String::new();
+            let (size, cut) = read_line_bounded(&mut buffer, &mut line, 9).unwrap();
+            (size, cut, line)
+        };
+        assert_eq!(read(), (6, false, "short\n".to_owned()));
+        assert_eq!(read(), (9, false, "exactly9\n".to_owned()));
+        // the rest of the line is skipped, not stored
+        assert_eq!(read(), (13, true, String::new()));
+        assert_eq!(read(), (5, false, "next\n".to_owned()));
+        assert_eq!(read(), (4, false, "last".to_owned()));
+        assert_eq!(read(), (0, false, String::new()));
+    }
+
+    #[test]
+    fn bounded_line_without_end() {
+        let mut buffer = BufReader::new(std::io::repeat(b'x').take(1 << 20));
+        let mut line = String::new();
+        let (size, cut) = read_line_bounded(&mut buffer, &mut line, 10).unwrap();
+        assert_eq!((size, cut, line.as_str()), (1 << 20, true, ""));
     }
 }
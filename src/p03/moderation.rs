@@ -0,0 +1,118 @@
+#[derive(Debug, PartialEq)]
+pub enum Verdict {
+    Pass,
+    Rewrite(String),
+    Drop(String),
+    // broadcast unchanged, but noted in the log and transcript
+    Flag(String),
+}
+
+// Consulted for every message, action and private message before it is sent out
+pub trait Moderator: Send {
+    fn moderate(&mut self, name: &str, room: &str, message: &str) -> Verdict;
+}
+
+#[allow(dead_code)]
+#[derive(Clone, Copy)]
+pub enum FilterAction {
+    Mask,
+    Drop,
+    Flag,
+}
+
+// Matches whole words case-insensitively, ignoring surrounding punctuation
+pub struct WordFilter {
+    words: Vec<String>,
+    action: FilterAction,
+}
+
+impl WordFilter {
+    pub fn new(words: &[&str], action: FilterAction) -> Self {
+        Self {
+            words: words.iter().map(|w| w.to_lowercase()).collect(),
+            action,
+        }
+    }
+
+    fn matches(&self, part: &str) -> bool {
+        let word = part.trim_matches(|c: char| c.is_ascii_punctuation());
+        !word.is_empty() && self.words.contains(&word.to_lowercase())
+    }
+}
+
+impl Moderator for WordFilter {
+    fn moderate(&mut self, _name: &str, _room: &str, message: &str) -> Verdict {
+        if !message.split(' ').any(|part| self.matches(part)) {
+            return Verdict::Pass;
+        }
+        match self.action {
+            FilterAction::Mask => Verdict::Rewrite(
+                message
+                    .split(' ')
+                    .map(|part| {
+                        if self.matches(part) {
+                            part.chars()
+                                .map(|c| if c.is_ascii_punctuation() { c } else { '*' })
+                                .collect()
+                        } else {
+                            part.to_owned()
+                        }
+                    })
+                    .intersperse(" ".to_owned())
+                    .collect(),
+            ),
+            FilterAction::Drop => Verdict::Drop("contains a filtered word".to_owned()),
+            FilterAction::Flag => Verdict::Flag("contains a filtered word".to_owned()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn moderate(action: FilterAction, message: &str) -> Verdict {
+        WordFilter::new(&["Darn", "heck"], action).moderate("alice", "#general", message)
+    }
+
+    #[test]
+    fn mask() {
+        assert_eq!(
+            moderate(FilterAction::Mask, "oh darn, what the HECK!"),
+            Verdict::Rewrite("oh ****, what the ****!".to_owned())
+        );
+    }
+
+    #[test]
+    fn drop() {
+        assert_eq!(
+            moderate(FilterAction::Drop, "darn"),
+            Verdict::Drop("contains a filtered word".to_owned())
+        );
+    }
+
+    #[test]
+    fn flag() {
+        assert_eq!(
+            moderate(FilterAction::Flag, "(Heck)"),
+            Verdict::Flag("contains a filtered word".to_owned())
+        );
+    }
+
+    #[test]
+    fn whole_words_only() {
+        for message in ["darned", "heckle", "da-rn", "", "!!!"] {
+            assert_eq!(moderate(FilterAction::Mask, message), Verdict::Pass);
+        }
+    }
+
+    #[test]
+    fn punctuation_inside_a_word() {
+        // only surrounding punctuation is trimmed
+        assert_eq!(moderate(FilterAction::Mask, "d'arn"), Verdict::Pass);
+        assert_eq!(
+            moderate(FilterAction::Mask, "...darn..."),
+            Verdict::Rewrite("...****...".to_owned())
+        );
+    }
+}
@@ -0,0 +1,124 @@
+use std::{
+    fs::{File, OpenOptions},
+    io::Write,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+// Appends `<unix time> <event>` lines to `path`. Once it grows over `max_bytes`, it is renamed to
+// `path.1` (shifting older ones up to `path.<keep>`, the oldest one being deleted) and a fresh
+// file is started.
+pub struct Transcript {
+    path: String,
+    max_bytes: u64,
+    keep: usize,
+    file: Option<File>,
+    written: u64,
+}
+
+impl Transcript {
+    pub fn new(path: &str, max_bytes: u64, keep: usize) -> Self {
+        let mut transcript = Self {
+            path: path.to_owned(),
+            max_bytes,
+            keep,
+            file: None,
+            written: 0,
+        };
+        transcript.open();
+        transcript
+    }
+
+    fn open(&mut self) {
+        let res: Result<File, std::io::Error> = try {
+            let file = OpenOptions::new()
+                .create(true)
+                .append(true)
+                .open(&self.path)?;
+            self.written = file.metadata()?.len();
+            file
+        };
+        match res {
+            Ok(file) => self.file = Some(file),
+            Err(e) => eprintln!("Error opening transcript {}: {:?}", self.path, e),
+        }
+    }
+
+    fn rotate(&mut self) {
+        self.file = None;
+        let res: Result<(), std::io::Error> = try {
+            for i in (1..self.keep).rev() {
+                let from = format!("{}.{}", self.path, i);
+                if std::fs::metadata(&from).is_ok() {
+                    std::fs::rename(&from, format!("{}.{}", self.path, i + 1))?;
+                }
+            }
+            if self.keep > 0 {
+                std::fs::rename(&self.path, format!("{}.1", self.path))?;
+            } else {
+                std::fs::remove_file(&self.path)?;
+            }
+        };
+        if let Err(e) = res {
+            eprintln!("Error rotating transcript {}: {:?}", self.path, e);
+        }
+        self.open();
+    }
+
+    pub fn record(&mut self, event: &str) {
+        if self.written >= self.max_bytes {
+            self.rotate();
+        }
+        let Some(file) = &mut self.file else { return; };
+        let time = SystemTime::now().duration_since(UNIX_EPOCH).unwrap();
+        let line = format!("{}.{:03} {}\n", time.as_secs(), time.subsec_millis(), event);
+        match file.write_all(line.as_bytes()) {
+            Ok(()) => self.written += line.len() as u64,
+            Err(e) => {
+                eprintln!("Error writing transcript {}: {:?}", self.path, e);
+                self.file = None;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn read(path: &str) -> Option<String> {
+        std::fs::read_to_string(path).ok()
+    }
+
+    fn events(contents: Option<String>) -> Vec<String> {
+        contents
+            .unwrap_or_default()
+            .lines()
+            .map(|line| line.split_once(' ').unwrap().1.to_owned())
+            .collect()
+    }
+
+    #[test]
+    fn rotate_shifts_and_keeps() {
+        let dir = std::env::temp_dir().join(format!("p03-transcript-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("transcript").to_str().unwrap().to_owned();
+
+        // every event fills the file, so the next one rotates it
+        let mut transcript = Transcript::new(&path, 1, 2);
+        for event in ["a", "b", "c", "d"] {
+            transcript.record(event);
+        }
+        assert_eq!(events(read(&path)), ["d"]);
+        assert_eq!(events(read(&format!("{}.1", path))), ["c"]);
+        assert_eq!(events(read(&format!("{}.2", path))), ["b"]);
+        assert_eq!(read(&format!("{}.3", path)), None);
+
+        let mut transcript = Transcript::new(&path, 1, 0);
+        transcript.record("e");
+        assert_eq!(events(read(&path)), ["e"]);
+        assert_eq!(events(read(&format!("{}.1", path))), ["c"]);
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,396 @@
+use std::{
+    io::{BufRead, BufReader, ErrorKind, Read, Write},
+    net::TcpStream,
+    sync::{Arc, Mutex},
+};
+
+const HANDSHAKE_GUID: &str = "258EAFA5-E914-47DA-95CA-C5AB0DC85B11";
+const MAX_MESSAGE_SIZE: u64 = 1 << 20;
+
+const OPCODE_CONTINUATION: u8 = 0x0;
+const OPCODE_TEXT: u8 = 0x1;
+const OPCODE_BINARY: u8 = 0x2;
+const OPCODE_CLOSE: u8 = 0x8;
+const OPCODE_PING: u8 = 0x9;
+const OPCODE_PONG: u8 = 0xA;
+
+// Reads the HTTP upgrade request and answers it. The returned reader yields the payload of every
+// message followed by a newline, and the writer sends each write as one text frame.
+pub fn accept(mut stream: TcpStream) -> std::io::Result<(WebSocketReader, WebSocketWriter)> {
+    let mut reader = BufReader::new(stream.try_clone()?);
+    let mut key = None;
+    loop {
+        let mut line = String::new();
+        if reader.read_line(&mut line)? == 0 {
+            return Err(ErrorKind::UnexpectedEof.into());
+        }
+        let line = line.trim_end();
+        if line.is_empty() {
+            break;
+        }
+        if let Some((header, value)) = line.split_once(':')
+            && header.trim().eq_ignore_ascii_case("sec-websocket-key")
+        {
+            key = Some(value.trim().to_owned());
+        }
+    }
+    let Some(key) = key else {
+        stream.write_all(b"HTTP/1.1 400 Bad Request\r\nContent-Length: 0\r\n\r\n")?;
+        return Err(std::io::Error::new(
+            ErrorKind::InvalidData,
+            "missing Sec-WebSocket-Key",
+        ));
+    };
+    let accept = base64(&sha1(format!("{}{}", key, HANDSHAKE_GUID).as_bytes()));
+    stream.write_all(
+        format!(
+            "HTTP/1.1 101 Switching Protocols\r\n\
+             Upgrade: websocket\r\n\
+             Connection: Upgrade\r\n\
+             Sec-WebSocket-Accept: {}\r\n\r\n",
+            accept
+        )
+        .as_bytes(),
+    )?;
+    let writer = WebSocketWriter {
+        stream: Arc::new(Mutex::new(stream)),
+    };
+    let reader = WebSocketReader {
+        reader,
+        writer: writer.clone(),
+        pending: Vec::new(),
+        position: 0,
+    };
+    Ok((reader, writer))
+}
+
+pub struct WebSocketReader {
+    reader: BufReader<TcpStream>,
+    // for answering pings and close frames
+    writer: WebSocketWriter,
+    pending: Vec<u8>,
+    position: usize,
+}
+
+impl WebSocketReader {
+    // None once the connection is closed
+    fn next_message(&mut self) -> std::io::Result<Option<Vec<u8>>> {
+        let mut message = Vec::new();
+        loop {
+            let mut header = [0; 2];
+            match self.reader.read_exact(&mut header) {
+                Ok(()) => {}
+                Err(e) if e.kind() == ErrorKind::UnexpectedEof => return Ok(None),
+                Err(e) => return Err(e),
+            }
+            let fin = header[0] & 0x80 != 0;
+            let opcode = header[0] & 0x0f;
+            let length = match header[1] & 0x7f {
+                126 => {
+                    let mut length = [0; 2];
+                    self.reader.read_exact(&mut length)?;
+                    u16::from_be_bytes(length) as u64
+                }
+                127 => {
+                    let mut length = [0; 8];
+                    self.reader.read_exact(&mut length)?;
+                    u64::from_be_bytes(length)
+                }
+                length => length as u64,
+            };
+            if length > MAX_MESSAGE_SIZE - message.len() as u64 {
+                return Err(std::io::Error::new(
+                    ErrorKind::InvalidData,
+                    "websocket message too large",
+                ));
+            }
+            let mut mask = [0; 4];
+            if header[1] & 0x80 != 0 {
+                self.reader.read_exact(&mut mask)?;
+            }
+            let mut payload = vec![0; length as usize];
+            self.reader.read_exact(&mut payload)?;
+            for (i, byte) in payload.iter_mut().enumerate() {
+                *byte ^= mask[i % 4];
+            }
+            match opcode {
+                OPCODE_CONTINUATION | OPCODE_TEXT | OPCODE_BINARY => {
+                    message.extend_from_slice(&payload);
+                    if fin {
+                        return Ok(Some(message));
+                    }
+                }
+                OPCODE_CLOSE => {
+                    let _ = self
+                        .writer
+                        .send_frame(OPCODE_CLOSE, &payload[..payload.len().min(2)]);
+                    return Ok(None);
+                }
+                OPCODE_PING => self.writer.send_frame(OPCODE_PONG, &payload)?,
+                _ => {}
+            }
+        }
+    }
+}
+
+impl Read for WebSocketReader {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        while self.position == self.pending.len() {
+            let Some(mut message) = self.next_message()? else { return Ok(0); };
+            message.push(b'\n');
+            self.pending = message;
+            self.position = 0;
+        }
+        let n = buf.len().min(self.pending.len() - self.position);
+        buf[..n].copy_from_slice(&self.pending[self.position..self.position + n]);
+        self.position += n;
+        Ok(n)
+    }
+}
+
+#[derive(Clone)]
+pub struct WebSocketWriter {
+    stream: Arc<Mutex<TcpStream>>,
+}
+
+impl WebSocketWriter {
+    fn send_frame(&self, opcode: u8, payload: &[u8]) -> std::io::Result<()> {
+        let mut frame = vec![0x80 | opcode];
+        match payload.len() {
+            length if length < 126 => frame.push(length as u8),
+            length if length <= u16::MAX as usize => {
+                frame.push(126);
+                frame.extend_from_slice(&(length as u16).to_be_bytes());
+            }
+            length => {
+                frame.push(127);
+                frame.extend_from_slice(&(length as u64).to_be_bytes());
+            }
+        }
+        frame.extend_from_slice(payload);
+        self.stream.lock().unwrap().write_all(&frame)
+    }
+}
+
+// the chat writes whole lines at once, which become one frame each, without the newline
+impl Write for WebSocketWriter {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        self.send_frame(OPCODE_TEXT, buf.strip_suffix(b"\n").unwrap_or(buf))?;
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+}
+
+fn sha1(data: &[u8]) -> [u8; 20] {
+    let mut state: [u32; 5] = [0x67452301, 0xEFCDAB89, 0x98BADCFE, 0x10325476, 0xC3D2E1F0];
+    let mut message = data.to_vec();
+    message.push(0x80);
+    while message.len() % 64 != 56 {
+        message.push(0);
+    }
+    message.extend_from_slice(&(data.len() as u64 * 8).to_be_bytes());
+    for block in message.chunks(64) {
+        let mut w = [0u32; 80];
+        for i in 0..16 {
+            w[i] = u32::from_be_bytes(block[i * 4..i * 4 + 4].try_into().unwrap());
+        }
+        for i in 16..80 {
+            w[i] = (w[i - 3] ^ w[i - 8] ^ w[i - 14] ^ w[i - 16]).rotate_left(1);
+        }
+        let [mut a, mut b, mut c, mut d, mut e] = state;
+        for (i, word) in w.iter().enumerate() {
+            let (f, k) = match i {
+                0..=19 => ((b & c) | (!b & d), 0x5A827999),
+                20..=39 => (b ^ c ^ d, 0x6ED9EBA1),
+                40..=59 => ((b & c) | (b & d) | (c & d), 0x8F1BBCDC),
+                _ => (b ^ c ^ d, 0xCA62C1D6),
+            };
+            let temp = a
+                .rotate_left(5)
+                .wrapping_add(f)
+                .wrapping_add(e)
+                .wrapping_add(k)
+                .wrapping_add(*word);
+            e = d;
+            d = c;
+            c = b.rotate_left(30);
+            b = a;
+            a = temp;
+        }
+        for (s, v) in state.iter_mut().zip([a, b, c, d, e]) {
+            *s = s.wrapping_add(v);
+        }
+    }
+    let mut digest = [0; 20];
+    for (i, s) in state.iter().enumerate() {
+        digest[i * 4..i * 4 + 4].copy_from_slice(&s.to_be_bytes());
+    }
+    digest
+}
+
+fn base64(data: &[u8]) -> String {
+    const ALPHABET: &[u8; 64] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+    let mut encoded = String::new();
+    for chunk in data.chunks(3) {
+        let bits = chunk.iter().enumerate().fold(0u32, |bits, (i, byte)| {
+            bits | (*byte as u32) << (16 - 8 * i)
+        });
+        for i in 0..4 {
+            if i <= chunk.len() {
+                encoded.push(ALPHABET[(bits >> (18 - 6 * i)) as usize & 63] as char);
+            } else {
+                encoded.push('=');
+            }
+        }
+    }
+    encoded
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::TcpListener;
+
+    use super::*;
+
+    fn hex(data: &[u8]) -> String {
+        data.iter().map(|b| format!("{:02x}", b)).collect()
+    }
+
+    #[test]
+    fn sha1_vectors() {
+        assert_eq!(hex(&sha1(b"")), "da39a3ee5e6b4b0d3255bfef95601890afd80709");
+        assert_eq!(
+            hex(&sha1(b"abc")),
+            "a9993e364706816aba3e25717850c26c9cd0d89d"
+        );
+        // two blocks of padding
+        assert_eq!(
+            hex(&sha1(
+                b"abcdbcdecdefdefgefghfghighijhijkijkljklmklmnlmnomnopnopq"
+            )),
+            "84983e441c3bd26ebaae4aa1f95129e5e54670f1"
+        );
+    }
+
+    #[test]
+    fn base64_padding() {
+        for (data, encoded) in [
+            ("", ""),
+            ("f", "Zg=="),
+            ("fo", "Zm8="),
+            ("foo", "Zm9v"),
+            ("foob", "Zm9vYg=="),
+            ("foobar", "Zm9vYmFy"),
+        ] {
+            assert_eq!(base64(data.as_bytes()), encoded);
+        }
+    }
+
+    // a masked client frame
+    fn frame(fin: bool, opcode: u8, payload: &[u8]) -> Vec<u8> {
+        let mask = [1, 2, 3, 4];
+        let mut frame = vec![if fin { 0x80 } else { 0 } | opcode];
+        match payload.len() {
+            length if length < 126 => frame.push(0x80 | length as u8),
+            length => {
+                frame.push(0x80 | 126);
+                frame.extend_from_slice(&(length as u16).to_be_bytes());
+            }
+        }
+        frame.extend_from_slice(&mask);
+        frame.extend(payload.iter().enumerate().map(|(i, b)| b ^ mask[i % 4]));
+        frame
+    }
+
+    // the client side of an accepted connection, with the handshake response
+    fn connect() -> (TcpStream, String, WebSocketReader, WebSocketWriter) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let mut client = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        client
+            .write_all(
+                b"GET /chat HTTP/1.1\r\n\
+                  Host: localhost\r\n\
+                  Upgrade: websocket\r\n\
+                  Connection: Upgrade\r\n\
+                  Sec-WebSocket-Key: dGhlIHNhbXBsZSBub25jZQ==\r\n\
+                  Sec-WebSocket-Version: 13\r\n\r\n",
+            )
+            .unwrap();
+        let (server, _) = listener.accept().unwrap();
+        let (reader, writer) = accept(server).unwrap();
+        let mut response = Vec::new();
+        while !response.ends_with(b"\r\n\r\n") {
+            let mut byte = [0];
+            client.read_exact(&mut byte).unwrap();
+            response.push(byte[0]);
+        }
+        (client, String::from_utf8(response).unwrap(), reader, writer)
+    }
+
+    #[test]
+    fn handshake() {
+        // the example from RFC 6455
+        let (_, response, _, _) = connect();
+        assert!(response.starts_with("HTTP/1.1 101 "));
+        assert!(response.contains("Sec-WebSocket-Accept: s3pPLMBiTxaQ9kYGzzhZRbK+xOo=\r\n"));
+    }
+
+    #[test]
+    fn fragmented_message_with_ping() {
+        let (mut client, _, reader, _) = connect();
+        let long = "x".repeat(200);
+        client
+            .write_all(&frame(false, OPCODE_TEXT, b"Hel"))
+            .unwrap();
+        client
+            .write_all(&frame(true, OPCODE_PING, b"ping"))
+            .unwrap();
+        client
+            .write_all(&frame(true, OPCODE_CONTINUATION, b"lo"))
+            .unwrap();
+        client
+            .write_all(&frame(true, OPCODE_TEXT, long.as_bytes()))
+            .unwrap();
+        client
+            .write_all(&frame(true, OPCODE_CLOSE, &[3, 232]))
+            .unwrap();
+        let lines = BufReader::new(reader)
+            .lines()
+            .collect::<Result<Vec<_>, _>>()
+            .unwrap();
+        assert_eq!(lines, ["Hello", long.as_str()]);
+
+        let mut replies = [0; 10];
+        client.read_exact(&mut replies).unwrap();
+        assert_eq!(replies, *b"\x8a\x04ping\x88\x02\x03\xe8");
+    }
+
+    #[test]
+    fn writes_text_frames() {
+        let (mut client, _, _, mut writer) = connect();
+        writer.write_all(b"* hi\n").unwrap();
+        let long = "y".repeat(300);
+        writer.write_all(long.as_bytes()).unwrap();
+        let mut frames = vec![0; 6 + 4 + 300];
+        client.read_exact(&mut frames).unwrap();
+        assert_eq!(frames[..6], *b"\x81\x04* hi");
+        assert_eq!(frames[6..10], [0x81, 126, 1, 44]);
+        assert_eq!(frames[10..], *long.as_bytes());
+    }
+
+    #[test]
+    fn oversized_continuation() {
+        let (mut client, _, mut reader, _) = connect();
+        client
+            .write_all(&frame(false, OPCODE_TEXT, b"abc"))
+            .unwrap();
+        // a continuation claiming a length that would overflow the running total
+        client.write_all(&[OPCODE_CONTINUATION, 127]).unwrap();
+        client.write_all(&u64::MAX.to_be_bytes()).unwrap();
+        let e = reader.read(&mut [0; 16]).unwrap_err();
+        assert_eq!(e.kind(), ErrorKind::InvalidData);
+    }
+}
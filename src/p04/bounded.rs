@@ -0,0 +1,344 @@
+use std::{
+    collections::{BTreeMap, BTreeSet, HashMap},
+    time::{Duration, Instant},
+};
+
+use super::{replication::Version, storage::Storage};
+
+// Log a summary every this many evictions
+const LOG_EVERY: u64 = 1000;
+
+#[derive(Clone, Copy)]
+pub struct Limits {
+    pub max_entries: Option<usize>,
+    // counted as key plus value bytes, ignoring bookkeeping overhead
+    pub max_bytes: Option<usize>,
+}
+
+#[derive(Default)]
+pub struct Stats {
+    pub evictions: u64,
+    pub expirations: u64,
+}
+
+struct Entry {
+    size: usize,
+    last_used: u64,
+    expires: Option<Instant>,
+    version: Version,
+}
+
+// Wraps a backend, expiring keys whose TTL has passed and evicting the least recently used keys
+// while over the limits. Expiry is lazy, checked whenever the store is accessed. TTLs are not
+// persisted, so entries recovered from a log on startup never expire.
+pub struct Bounded {
+    inner: Box<dyn Storage>,
+    limits: Limits,
+    entries: HashMap<Vec<u8>, Entry>,
+    // last use -> key, the first entry is the least recently used
+    lru: BTreeMap<u64, Vec<u8>>,
+    expiry: BTreeSet<(Instant, Vec<u8>)>,
+    clock: u64,
+    bytes: usize,
+    pub stats: Stats,
+}
+
+impl Bounded {
+    pub fn new(inner: Box<dyn Storage>, limits: Limits) -> Self {
+        let mut bounded = Self {
+            inner,
+            limits,
+            entries: HashMap::new(),
+            lru: BTreeMap::new(),
+            expiry: BTreeSet::new(),
+            clock: 0,
+            bytes: 0,
+            stats: Stats::default(),
+        };
+        for (key, value) in bounded.inner.entries() {
+            bounded.track(key, value.len(), None, Version::default());
+        }
+        bounded.evict();
+        bounded
+    }
+
+    pub fn insert_with_ttl(
+        &mut self,
+        key: Vec<u8>,
+        value: Vec<u8>,
+        ttl: Option<Duration>,
+        version: Version,
+    ) {
+        self.expire();
+        self.untrack(&key);
+        let size = value.len();
+        self.inner.insert(key.clone(), value);
+        // a TTL too far in the future to represent never expires
+        let expires = ttl.and_then(|ttl| Instant::now().checked_add(ttl));
+        self.track(key, size, expires, version);
+        self.evict();
+    }
+
+    // None for keys that are not stored
+    pub fn version(&mut self, key: &[u8]) -> Option<Version> {
+        self.expire();
+        self.entries.get(key).map(|entry| entry.version)
+    }
+
+    // (entries, bytes) of the keys starting with `prefix`
+    pub fn usage(&mut self, prefix: &[u8]) -> (usize, usize) {
+        self.expire();
+        if prefix.is_empty() {
+            return (self.entries.len(), self.bytes);
+        }
+        self.entries
+            .iter()
+            .filter(|(key, _)| key.starts_with(prefix))
+            .fold((0, 0), |(count, bytes), (_, entry)| {
+                (count + 1, bytes + entry.size)
+            })
+    }
+
+    pub fn keys(&mut self, prefix: &[u8]) -> Vec<Vec<u8>> {
+        self.expire();
+        self.entries
+            .keys()
+            .filter(|key| key.starts_with(prefix))
+            .cloned()
+            .collect()
+    }
+
+    // recomputes the bookkeeping from the backend, after a panic may have left it half-updated.
+    // Expiry and versions are kept for keys still tracked, but the order of use is lost.
+    pub fn rebuild(&mut self) {
+        let tracked = std::mem::take(&mut self.entries);
+        self.lru.clear();
+        self.expiry.clear();
+        self.bytes = 0;
+        for (key, value) in self.inner.entries() {
+            let (expires, version) = tracked
+                .get(&key)
+                .map_or((None, Version::default()), |entry| {
+                    (entry.expires, entry.version)
+                });
+            self.track(key, value.len(), expires, version);
+        }
+        self.expire();
+        self.evict();
+    }
+
+    fn track(
+        &mut self,
+        key: Vec<u8>,
+        value_size: usize,
+        expires: Option<Instant>,
+        version: Version,
+    ) {
+        self.clock += 1;
+        let size = key.len() + value_size;
+        self.bytes += size;
+        self.lru.insert(self.clock, key.clone());
+        if let Some(expires) = expires {
+            self.expiry.insert((expires, key.clone()));
+        }
+        let entry = Entry {
+            size,
+            last_used: self.clock,
+            expires,
+            version,
+        };
+        self.entries.insert(key, entry);
+    }
+
+    fn untrack(&mut self, key: &[u8]) -> bool {
+        let Some(entry) = self.entries.remove(key) else { return false; };
+        self.bytes -= entry.size;
+        self.lru.remove(&entry.last_used);
+        if let Some(expires) = entry.expires {
+            self.expiry.remove(&(expires, key.to_vec()));
+        }
+        true
+    }
+
+    fn touch(&mut self, key: &[u8]) {
+        let Some(entry) = self.entries.get_mut(key) else { return; };
+        self.clock += 1;
+        let key = self.lru.remove(&entry.last_used).unwrap();
+        entry.last_used = self.clock;
+        self.lru.insert(self.clock, key);
+    }
+
+    fn expire(&mut self) {
+        let now = Instant::now();
+        while let Some((expires, _)) = self.expiry.first()
+            && *expires <= now
+        {
+            let (_, key) = self.expiry.pop_first().unwrap();
+            self.untrack(&key);
+            self.inner.remove(&key);
+            self.stats.expirations += 1;
+        }
+    }
+
+    fn over_limits(&self) -> bool {
+        self.limits
+            .max_entries
+            .is_some_and(|max| self.entries.len() > max)
+            || self.limits.max_bytes.is_some_and(|max| self.bytes > max)
+    }
+
+    fn evict(&mut self) {
+        while self.over_limits() {
+            let Some((_, key)) = self.lru.first_key_value() else { break; };
+            let key = key.clone();
+            self.untrack(&key);
+            self.inner.remove(&key);
+            self.stats.evictions += 1;
+            if self.stats.evictions.is_multiple_of(LOG_EVERY) {
+                eprintln!(
+                    "Evicted {} keys so far ({} expired), holding {} keys in {} bytes",
+                    self.stats.evictions,
+                    self.stats.expirations,
+                    self.entries.len(),
+                    self.bytes
+                );
+            }
+        }
+    }
+}
+
+impl Storage for Bounded {
+    fn get(&mut self, key: &[u8]) -> Option<Vec<u8>> {
+        self.expire();
+        self.touch(key);
+        self.inner.get(key)
+    }
+
+    fn insert(&mut self, key: Vec<u8>, value: Vec<u8>) {
+        self.insert_with_ttl(key, value, None, Version::default());
+    }
+
+    fn remove(&mut self, key: &[u8]) {
+        self.expire();
+        if self.untrack(key) {
+            self.inner.remove(key);
+        }
+    }
+
+    fn entries(&self) -> Vec<(Vec<u8>, Vec<u8>)> {
+        let now = Instant::now();
+        let mut entries = self.inner.entries();
+        entries.retain(|(key, _)| {
+            self.entries
+                .get(key)
+                .and_then(|entry| entry.expires)
+                .is_none_or(|expires| expires > now)
+        });
+        entries
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::p04::storage::MemoryStorage;
+
+    fn bounded(max_entries: Option<usize>, max_bytes: Option<usize>) -> Bounded {
+        let limits = Limits {
+            max_entries,
+            max_bytes,
+        };
+        Bounded::new(Box::new(MemoryStorage::default()), limits)
+    }
+
+    fn insert(bounded: &mut Bounded, key: &str, value: &str) {
+        bounded.insert(key.into(), value.into());
+    }
+
+    fn keys(bounded: &mut Bounded) -> Vec<String> {
+        let mut keys = bounded
+            .keys(b"")
+            .into_iter()
+            .map(|key| String::from_utf8(key).unwrap())
+            .collect::<Vec<_>>();
+        keys.sort();
+        keys
+    }
+
+    #[test]
+    fn evicts_least_recently_used() {
+        let mut bounded = bounded(Some(3), None);
+        for key in ["a", "b", "c"] {
+            insert(&mut bounded, key, "v");
+        }
+        // reading `a` makes `b` the least recently used
+        assert_eq!(bounded.get(b"a"), Some(b"v".to_vec()));
+        insert(&mut bounded, "d", "v");
+        assert_eq!(keys(&mut bounded), ["a", "c", "d"]);
+        assert_eq!(bounded.get(b"b"), None);
+        // and replacing `c` protects it
+        insert(&mut bounded, "c", "w");
+        insert(&mut bounded, "e", "v");
+        assert_eq!(keys(&mut bounded), ["c", "d", "e"]);
+        assert_eq!(bounded.stats.evictions, 2);
+    }
+
+    #[test]
+    fn evicts_down_to_max_bytes() {
+        let mut bounded = bounded(None, Some(10));
+        insert(&mut bounded, "a", "1234");
+        insert(&mut bounded, "b", "1234");
+        assert_eq!(bounded.usage(b""), (2, 10));
+        // 6 bytes, so both older keys have to go
+        insert(&mut bounded, "c", "12345678");
+        assert_eq!(keys(&mut bounded), ["c"]);
+        assert_eq!(bounded.usage(b""), (1, 9));
+        assert_eq!(bounded.stats.evictions, 2);
+        // a key that doesn't fit at all is evicted right away
+        insert(&mut bounded, "d", "1234567890");
+        assert_eq!(keys(&mut bounded), Vec::<String>::new());
+        assert_eq!(bounded.usage(b""), (0, 0));
+        assert_eq!(bounded.stats.evictions, 4);
+    }
+
+    #[test]
+    fn expires_after_ttl() {
+        let mut bounded = bounded(None, None);
+        let ttl = Some(Duration::from_millis(50));
+        bounded.insert_with_ttl(b"short".to_vec(), b"v".to_vec(), ttl, Version::default());
+        insert(&mut bounded, "long", "v");
+        assert_eq!(bounded.get(b"short"), Some(b"v".to_vec()));
+        assert_eq!(bounded.entries().len(), 2);
+
+        std::thread::sleep(Duration::from_millis(100));
+        // left out of `entries` even before the expiry is noticed
+        assert_eq!(bounded.entries(), [(b"long".to_vec(), b"v".to_vec())]);
+        assert_eq!(bounded.get(b"short"), None);
+        assert_eq!(bounded.usage(b""), (1, 5));
+        assert_eq!(bounded.stats.expirations, 1);
+        assert_eq!(bounded.stats.evictions, 0);
+    }
+
+    #[test]
+    fn insert_clears_ttl() {
+        let mut bounded = bounded(None, None);
+        let ttl = Some(Duration::from_millis(50));
+        bounded.insert_with_ttl(b"k".to_vec(), b"v".to_vec(), ttl, Version::default());
+        insert(&mut bounded, "k", "w");
+        std::thread::sleep(Duration::from_millis(100));
+        assert_eq!(bounded.get(b"k"), Some(b"w".to_vec()));
+        assert_eq!(bounded.stats.expirations, 0);
+    }
+
+    #[test]
+    fn usage_by_prefix() {
+        let mut bounded = bounded(None, None);
+        insert(&mut bounded, "@t/a", "12");
+        insert(&mut bounded, "@t/b", "");
+        insert(&mut bounded, "@u/a", "123");
+        assert_eq!(bounded.usage(b"@t/"), (2, 10));
+        assert_eq!(bounded.usage(b""), (3, 17));
+        bounded.remove(b"@t/a");
+        assert_eq!(bounded.usage(b"@t/"), (1, 4));
+    }
+}
@@ -0,0 +1,86 @@
+use std::sync::atomic::{AtomicU64, Ordering};
+
+use super::{sharded::Sharded, MAX_DATAGRAM_SIZE};
+
+// Read-only keys, answered as if they were stored:
+// - `stats`, `entries=<n> bytes=<n> inserts=<n> retrieves=<n> evictions=<n> expirations=<n>`
+// - `keys?prefix=<prefix>`, the number of matching keys followed by as many of them as fit in the
+//   response, in order, each on its own line
+// A tenant namespaces its keys by starting them with `@<tenant>/`. Both keys work within a
+// namespace too, e.g. `@acme/stats` only counts entries and bytes of keys starting with `@acme/`,
+// and `@acme/keys?prefix=a` lists `@acme/a...` without the namespace. Request counts are only kept
+// across all tenants, so they are left out of a tenant's stats.
+#[derive(Default)]
+pub struct Counters {
+    pub inserts: AtomicU64,
+    pub retrieves: AtomicU64,
+}
+
+const STATS: &[u8] = b"stats";
+const KEYS: &[u8] = b"keys?prefix=";
+
+// inserts are ignored for these keys
+pub fn is_reserved(key: &[u8]) -> bool {
+    let (_, key) = split_namespace(key);
+    key == STATS || key.starts_with(KEYS)
+}
+
+// the value to respond with, None if `msg` is not an introspection key
+pub fn introspect(storage: &Sharded, counters: &Counters, msg: &[u8]) -> Option<Vec<u8>> {
+    let (namespace, request) = split_namespace(msg);
+    if request == STATS {
+        let (mut entries, mut bytes, mut evictions, mut expirations) = (0, 0, 0, 0);
+        for mut shard in storage.shards() {
+            let usage = shard.usage(namespace);
+            entries += usage.0;
+            bytes += usage.1;
+            evictions += shard.stats.evictions;
+            expirations += shard.stats.expirations;
+        }
+        let stats = if namespace.is_empty() {
+            format!(
+                "entries={} bytes={} inserts={} retrieves={} evictions={} expirations={}",
+                entries,
+                bytes,
+                counters.inserts.load(Ordering::Relaxed),
+                counters.retrieves.load(Ordering::Relaxed),
+                evictions,
+                expirations
+            )
+        } else {
+            format!("entries={} bytes={}", entries, bytes)
+        };
+        return Some(stats.into_bytes());
+    }
+
+    let prefix = request.strip_prefix(KEYS)?;
+    let prefix = [namespace, prefix].concat();
+    let mut keys = storage
+        .shards()
+        .flat_map(|mut shard| shard.keys(&prefix))
+        .collect::<Vec<_>>();
+    keys.sort();
+    let mut value = keys.len().to_string().into_bytes();
+    // the response also echoes the request and a `=`
+    let limit = (MAX_DATAGRAM_SIZE - 1).saturating_sub(msg.len() + 1);
+    for key in keys {
+        let key = &key[namespace.len()..];
+        if value.len() + 1 + key.len() > limit {
+            break;
+        }
+        value.push(b'\n');
+        value.extend_from_slice(key);
+    }
+    Some(value)
+}
+
+// (`@<tenant>/` or nothing, the rest)
+fn split_namespace(msg: &[u8]) -> (&[u8], &[u8]) {
+    if msg.starts_with(b"@")
+        && let Some(i) = msg.iter().position(|b| *b == b'/')
+    {
+        msg.split_at(i + 1)
+    } else {
+        (&[], msg)
+    }
+}
@@ -1,27 +1,459 @@
+mod bounded;
+mod introspection;
+mod replication;
+mod sharded;
+mod storage;
+
+use std::{
+    hash::{DefaultHasher, Hash, Hasher},
+    net::{SocketAddr, UdpSocket},
+    panic::{self, AssertUnwindSafe},
+    sync::{
+        atomic::Ordering,
+        mpsc::{self, TrySendError},
+        Arc,
+    },
+    time::{Duration, Instant},
+};
+
+use bounded::{Bounded, Limits};
+use introspection::Counters;
+use replication::{Replicator, Version};
+use sharded::Sharded;
+use storage::{FsyncPolicy, MemoryStorage, Storage};
+
+const PORT: u16 = 1200;
+// Requests and responses must be shorter than this, larger requests are dropped
+const MAX_DATAGRAM_SIZE: usize = 1000;
+const VERSION: &[u8] = b"Unusual Database Program v0.1";
+const STORAGE: StorageBackend = StorageBackend::Memory;
+// Least recently used keys are evicted beyond these. Each shard gets an even share (rounded up) and
+// evicts its own least recently used keys, so with several shards the order is only roughly LRU
+// overall.
+const LIMITS: Limits = Limits {
+    max_entries: None,
+    max_bytes: Some(64 << 20),
+};
+// Accept `!ttl <seconds> key=value` to insert a key that expires. Off by default, since it is
+// also a valid plain insert of the key `!ttl <seconds> key`
+const EXTENDED_SYNTAX: bool = false;
+// Answer `stats` and `keys?prefix=` and namespace keys by tenant, see introspection.rs. Off by
+// default, since `keys?prefix=...` is also a valid plain insert
+const INTROSPECTION: bool = false;
+const WORKERS: usize = 4;
+// With a log backend and more than one shard, each shard keeps its own log at `<path>.<shard>`
+const SHARDS: usize = 16;
+// Requests waiting for a busy worker beyond this are dropped
+const WORKER_QUEUE_LENGTH: usize = 1024;
+// Keep in sync with other instances, see replication.rs
+const REPLICATION: Option<Replication> = None;
+
+struct Replication {
+    // for peer traffic only, should not be reachable by clients
+    address: &'static str,
+    // every other instance's replication address
+    peers: &'static [&'static str],
+}
+
+#[allow(dead_code)]
+enum StorageBackend {
+    Memory,
+    Log {
+        path: &'static str,
+        fsync: FsyncPolicy,
+    },
+}
+
 pub fn main() {
-    let socket = std::net::UdpSocket::bind("0.0.0.0:1200").unwrap();
-    let mut storage = std::collections::HashMap::new();
+    // `4 [<port> [<replication address> <peer>...]]` overrides PORT and REPLICATION, to run several
+    // instances on one machine
+    let args = std::env::args().skip(2).collect::<Vec<_>>();
+    let port = args.first().map_or(PORT, |port| port.parse().unwrap());
+    let replication = match (args.get(1), REPLICATION) {
+        (Some(address), _) => Some((address.clone(), args[2..].to_vec())),
+        (None, Some(Replication { address, peers })) => Some((
+            address.to_string(),
+            peers.iter().map(|peer| peer.to_string()).collect(),
+        )),
+        (None, None) => None,
+    };
+
+    let socket = UdpSocket::bind(("0.0.0.0", port)).unwrap();
+    let backends: Vec<Box<dyn Storage>> = match STORAGE {
+        StorageBackend::Memory => (0..SHARDS)
+            .map(|_| Box::new(MemoryStorage::default()) as _)
+            .collect(),
+        StorageBackend::Log { path, fsync } => storage::open_sharded(path, SHARDS, fsync)
+            .unwrap()
+            .into_iter()
+            .map(|log| Box::new(log) as _)
+            .collect(),
+    };
+    let limits = Limits {
+        max_entries: LIMITS.max_entries.map(|max| max.div_ceil(SHARDS)),
+        max_bytes: LIMITS.max_bytes.map(|max| max.div_ceil(SHARDS)),
+    };
+    let shards = backends
+        .into_iter()
+        .map(|backend| Bounded::new(backend, limits))
+        .collect();
+    let storage = Arc::new(Sharded::new(shards));
+    let counters = Arc::new(Counters::default());
+
+    let replicator = replication.map(|(address, peers)| {
+        let replicator = Arc::new(Replicator::new(&address, &peers).unwrap());
+        let (replicator2, storage) = (replicator.clone(), storage.clone());
+        std::thread::spawn(move || replicator2.serve(&storage));
+        let replicator2 = replicator.clone();
+        std::thread::spawn(move || replicator2.resync());
+        replicator
+    });
+
+    // Requests are handed to a worker chosen by the sender's address, and each worker handles its
+    // requests in order, so a retrieve always observes an earlier insert from the same client
+    let workers = (0..WORKERS)
+        .map(|_| {
+            let (sender, receiver) =
+                mpsc::sync_channel::<(Vec<u8>, SocketAddr)>(WORKER_QUEUE_LENGTH);
+            let socket = socket.try_clone().unwrap();
+            let storage = storage.clone();
+            let replicator = replicator.clone();
+            let counters = counters.clone();
+            std::thread::spawn(move || {
+                for (msg, addr) in receiver {
+                    // a panicking request is dropped, the worker carries on with the next one
+                    let res = panic::catch_unwind(AssertUnwindSafe(|| {
+                        handle(
+                            &storage,
+                            replicator.as_deref(),
+                            &counters,
+                            &socket,
+                            &msg,
+                            addr,
+                        )
+                    }));
+                    if res.is_err() {
+                        eprintln!("Dropping request from {}, handling it panicked", addr);
+                    }
+                }
+            });
+            sender
+        })
+        .collect::<Vec<_>>();
 
     loop {
-        let mut buf = [0; 1000];
-        let (size, addr) = socket.recv_from(&mut buf).unwrap();
-        let msg = std::str::from_utf8(&buf[..size]).unwrap();
-        if msg == "version" {
-            socket
-                .send_to(b"version=Unusual Database Program v0.1", addr)
-                .unwrap();
-            continue;
+        // larger than the limit, so that oversized datagrams are recognized rather than truncated
+        let mut buf = [0; MAX_DATAGRAM_SIZE + 1];
+        let (size, addr) = match socket.recv_from(&mut buf) {
+            Ok(x) => x,
+            Err(e) => {
+                eprintln!("Error receiving datagram: {:?}", e);
+                continue;
+            }
+        };
+        let mut hasher = DefaultHasher::new();
+        addr.hash(&mut hasher);
+        let worker = &workers[hasher.finish() as usize % WORKERS];
+        // like the network would, drop requests rather than queue them without bound
+        match worker.try_send((buf[..size].to_vec(), addr)) {
+            Ok(()) => {}
+            Err(TrySendError::Full(_)) => {
+                eprintln!("Dropping request from {}, worker is busy", addr)
+            }
+            Err(TrySendError::Disconnected(_)) => {
+                eprintln!("Dropping request from {}, worker has exited", addr)
+            }
+        }
+    }
+}
+
+fn handle(
+    storage: &Sharded,
+    replicator: Option<&Replicator>,
+    counters: &Counters,
+    socket: &UdpSocket,
+    msg: &[u8],
+    addr: SocketAddr,
+) {
+    let Some(response) = process(storage, replicator, counters, INTROSPECTION, msg, addr) else {
+        return;
+    };
+    if let Err(e) = socket.send_to(&response, addr) {
+        eprintln!("Error sending response to {}: {:?}", addr, e);
+    }
+}
+
+// the response to send for a request from `addr`, if any
+fn process(
+    storage: &Sharded,
+    replicator: Option<&Replicator>,
+    counters: &Counters,
+    introspection: bool,
+    mut msg: &[u8],
+    addr: SocketAddr,
+) -> Option<Vec<u8>> {
+    if msg.len() >= MAX_DATAGRAM_SIZE {
+        eprintln!("Dropping oversized request from {}", addr);
+        return None;
+    }
+    let mut ttl = None;
+    if EXTENDED_SYNTAX && let Some(rest) = msg.strip_prefix(b"!ttl ") {
+        let Some((seconds, rest)) = parse_ttl(rest) else {
+            eprintln!("Dropping malformed ttl request from {}", addr);
+            return None;
+        };
+        ttl = Some(seconds);
+        msg = rest;
+    }
+    if introspection
+        && ttl.is_none()
+        && let Some(value) = introspection::introspect(storage, counters, msg)
+    {
+        counters.retrieves.fetch_add(1, Ordering::Relaxed);
+        return response(msg, &value);
+    }
+    if let Some(i) = msg.iter().position(|b| *b == b'=') {
+        let (key, value) = (&msg[..i], &msg[i + 1..]);
+        if key == b"version" {
+            eprintln!("Ignoring attempt to set version from {}", addr);
+        } else if introspection && introspection::is_reserved(key) {
+            eprintln!(
+                "Ignoring attempt to set {:?} from {}",
+                String::from_utf8_lossy(key),
+                addr
+            );
+        } else {
+            counters.inserts.fetch_add(1, Ordering::Relaxed);
+            let mut shard = storage.shard(key);
+            // under the shard lock, so that the order of versions matches the order of inserts
+            let version = replicator.map_or(Version::default(), |r| r.tick());
+            shard.insert_with_ttl(key.to_vec(), value.to_vec(), ttl, version);
+            drop(shard);
+            if let Some(replicator) = replicator {
+                replicator.forward(key, value, version);
+            }
+        }
+        return None;
+    } else if ttl.is_some() {
+        eprintln!("Dropping ttl request without a value from {}", addr);
+        return None;
+    }
+
+    counters.retrieves.fetch_add(1, Ordering::Relaxed);
+    let value = if msg == b"version" {
+        VERSION.to_vec()
+    } else {
+        storage.shard(msg).get(msg).unwrap_or_default()
+    };
+    response(msg, &value)
+}
+
+fn response(msg: &[u8], value: &[u8]) -> Option<Vec<u8>> {
+    let response = [msg, b"=", value].concat();
+    if response.len() >= MAX_DATAGRAM_SIZE {
+        eprintln!(
+            "Not sending oversized response for {:?}",
+            String::from_utf8_lossy(msg)
+        );
+        return None;
+    }
+    Some(response)
+}
+
+// `<seconds> <rest>`, rejecting TTLs that expire further out than can be represented
+fn parse_ttl(msg: &[u8]) -> Option<(Duration, &[u8])> {
+    let i = msg.iter().position(|b| *b == b' ')?;
+    let seconds = std::str::from_utf8(&msg[..i]).ok()?.parse::<u64>().ok()?;
+    let ttl = Duration::from_secs(seconds);
+    Instant::now().checked_add(ttl)?;
+    Some((ttl, &msg[i + 1..]))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    struct Db {
+        storage: Sharded,
+        counters: Counters,
+        introspection: bool,
+    }
+
+    impl Db {
+        fn new() -> Self {
+            let shards = (0..4)
+                .map(|_| Bounded::new(Box::new(MemoryStorage::default()), LIMITS))
+                .collect();
+            Self {
+                storage: Sharded::new(shards),
+                counters: Counters::default(),
+                introspection: false,
+            }
+        }
+
+        fn with_introspection() -> Self {
+            Self {
+                introspection: true,
+                ..Self::new()
+            }
+        }
+
+        fn request(&self, msg: &[u8]) -> Option<Vec<u8>> {
+            let addr = "127.0.0.1:5000".parse().unwrap();
+            let introspection = self.introspection;
+            process(
+                &self.storage,
+                None,
+                &self.counters,
+                introspection,
+                msg,
+                addr,
+            )
+        }
+    }
+
+    #[test]
+    fn insert_and_retrieve() {
+        let db = Db::new();
+        assert_eq!(db.request(b"foo"), Some(b"foo=".to_vec()));
+        assert_eq!(db.request(b"foo=bar"), None);
+        assert_eq!(db.request(b"foo"), Some(b"foo=bar".to_vec()));
+        assert_eq!(db.request(b"foo="), None);
+        assert_eq!(db.request(b"foo"), Some(b"foo=".to_vec()));
+    }
+
+    #[test]
+    fn request_size_cutoff() {
+        let db = Db::new();
+        let key = vec![b'k'; MAX_DATAGRAM_SIZE - 3];
+        // 999 bytes
+        assert_eq!(db.request(&[&key[..], b"=v"].concat()), None);
+        assert_eq!(db.request(&key), Some([&key[..], b"=v"].concat()));
+        // 1000 bytes are dropped, before they are parsed
+        assert_eq!(db.request(&[&key[..], b"=vv"].concat()), None);
+        assert_eq!(db.request(&key), Some([&key[..], b"=v"].concat()));
+        let long_key = vec![b'k'; MAX_DATAGRAM_SIZE];
+        assert_eq!(db.request(&long_key), None);
+    }
+
+    #[test]
+    fn oversized_response() {
+        // an insert is as long as the response to its key, but replicated values or introspection
+        // can be longer
+        let value = vec![b'v'; MAX_DATAGRAM_SIZE - 4];
+        assert_eq!(response(b"key", &value), None);
+        assert_eq!(response(b"ke", &value), Some([b"ke=", &value[..]].concat()));
+    }
+
+    #[test]
+    fn parse_ttl_bounds() {
+        assert_eq!(
+            parse_ttl(b"60 k=v"),
+            Some((Duration::from_secs(60), &b"k=v"[..]))
+        );
+        assert_eq!(parse_ttl(b"18446744073709551615 k=v"), None);
+        assert_eq!(parse_ttl(b"-1 k=v"), None);
+        assert_eq!(parse_ttl(b"60"), None);
+    }
+
+    #[test]
+    fn unrepresentable_expiry() {
+        let mut shard = Bounded::new(Box::new(MemoryStorage::default()), LIMITS);
+        let ttl = Some(Duration::from_secs(u64::MAX));
+        shard.insert_with_ttl(b"k".to_vec(), b"v".to_vec(), ttl, Version::default());
+        assert_eq!(shard.get(b"k"), Some(b"v".to_vec()));
+    }
+
+    #[test]
+    fn non_utf8_keys() {
+        let db = Db::new();
+        db.request(b"\xff\xfe=\x80");
+        assert_eq!(db.request(b"\xff\xfe"), Some(b"\xff\xfe=\x80".to_vec()));
+    }
+
+    #[test]
+    fn version_is_read_only() {
+        let db = Db::new();
+        let version = [b"version=", VERSION].concat();
+        assert_eq!(db.request(b"version"), Some(version.clone()));
+        assert_eq!(db.request(b"version=hacked"), None);
+        assert_eq!(db.request(b"version"), Some(version));
+    }
+
+    #[test]
+    fn key_ends_at_first_equals() {
+        let db = Db::new();
+        db.request(b"foo=bar=baz");
+        assert_eq!(db.request(b"foo"), Some(b"foo=bar=baz".to_vec()));
+        db.request(b"=foo==");
+        assert_eq!(db.request(b""), Some(b"=foo==".to_vec()));
+    }
+
+    #[test]
+    fn key_listing_fills_response() {
+        let db = Db::with_introspection();
+        for i in 0..99 {
+            db.request(format!("key{:08}=v", i).as_bytes());
         }
-        if let Some(i) = msg.find('=') {
-            let key = msg[..i].to_owned();
-            let value = msg[i + 1..].to_owned();
-            storage.insert(key, value);
-            continue;
+        // `keys?prefix==99`, then 82 of the 11 byte keys fit exactly
+        let response = db.request(b"keys?prefix=").unwrap();
+        assert_eq!(response.len(), MAX_DATAGRAM_SIZE - 1);
+        let mut lines = response.split(|b| *b == b'\n');
+        assert_eq!(lines.next(), Some(&b"keys?prefix==99"[..]));
+        let keys = lines.collect::<Vec<_>>();
+        assert_eq!(keys.len(), 82);
+        assert_eq!(keys[81], b"key00000081");
+        // one more byte of request leaves no room for the last key
+        let response = db.request(b"keys?prefix=k").unwrap();
+        assert_eq!(response.split(|b| *b == b'\n').count(), 82);
+    }
+
+    #[test]
+    fn introspection_namespaces() {
+        let db = Db::with_introspection();
+        for msg in [&b"@t/a=1"[..], b"@t/b=22", b"a=x", b"@u/a=y"] {
+            db.request(msg);
         }
+        assert_eq!(
+            db.request(b"@t/keys?prefix="),
+            Some(b"@t/keys?prefix==2\na\nb".to_vec())
+        );
+        assert_eq!(
+            db.request(b"@t/keys?prefix=b"),
+            Some(b"@t/keys?prefix=b=1\nb".to_vec())
+        );
+        assert_eq!(
+            db.request(b"@t/stats"),
+            Some(b"@t/stats=entries=2 bytes=11".to_vec())
+        );
+        assert_eq!(
+            db.request(b"stats"),
+            Some(
+                b"stats=entries=4 bytes=18 inserts=4 retrieves=3 evictions=0 expirations=0"
+                    .to_vec()
+            )
+        );
+    }
 
-        let value = storage.get(msg).map_or("", |v| v.as_str());
-        socket
-            .send_to(format!("{}={}", msg, value).as_bytes(), addr)
-            .unwrap();
+    #[test]
+    fn introspection_keys_are_read_only() {
+        let db = Db::with_introspection();
+        assert_eq!(db.request(b"stats=x"), None);
+        assert_eq!(db.request(b"@t/stats=x"), None);
+        // a listing of keys starting with `a=x`
+        assert_eq!(
+            db.request(b"keys?prefix=a=x"),
+            Some(b"keys?prefix=a=x=0".to_vec())
+        );
+        assert_eq!(
+            db.request(b"stats"),
+            Some(
+                b"stats=entries=0 bytes=0 inserts=0 retrieves=1 evictions=0 expirations=0".to_vec()
+            )
+        );
+        // they are plain keys without introspection
+        let db = Db::new();
+        db.request(b"stats=x");
+        assert_eq!(db.request(b"stats"), Some(b"stats=x".to_vec()));
     }
 }
@@ -0,0 +1,385 @@
+use std::{
+    hash::{BuildHasher, RandomState},
+    net::{SocketAddr, ToSocketAddrs, UdpSocket},
+    sync::atomic::{AtomicBool, AtomicU64, Ordering},
+    time::Duration,
+};
+
+use super::{sharded::Sharded, storage::Storage, MAX_DATAGRAM_SIZE};
+
+const RESYNC_INTERVAL: Duration = Duration::from_secs(1);
+
+// Orders inserts of the same key across instances, the highest version wins. Keys that were never
+// replicated (such as those recovered from a log) have the lowest version, so a peer's copy wins.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, PartialOrd, Ord)]
+pub struct Version {
+    // Lamport clock
+    pub clock: u64,
+    // breaks ties between inserts made concurrently on different instances
+    pub node: u64,
+}
+
+// Peers exchange datagrams on a separate socket from clients:
+// - `U<clock u64><node u64><key length u16><key><value>`, big-endian, an insert
+// - `R`, asks the peer to send every entry it holds as `S` datagrams, followed by a `D`
+// - `S`, an entry in answer to an `R`, encoded like `U`
+// - `D<count u64>`, the end of the answer to an `R`, after `count` entries
+// Each instance forwards the inserts it receives from clients to all peers, but never forwards
+// inserts received from a peer, so every instance has to list all the others. Expiry stays local to
+// the instance that received a `!ttl` insert.
+pub struct Replicator {
+    socket: UdpSocket,
+    peers: Vec<Peer>,
+    node: u64,
+    clock: AtomicU64,
+}
+
+struct Peer {
+    // every address the peer's name resolved to, datagrams from any of them are accepted
+    addresses: Vec<SocketAddr>,
+    // where datagrams to the peer are sent, of the same family as the local socket
+    address: SocketAddr,
+    // `S` datagrams received since the last `R`
+    received: AtomicU64,
+    // whether it has answered a resync with all of its entries
+    synced: AtomicBool,
+}
+
+impl Peer {
+    fn is(&self, addr: SocketAddr) -> bool {
+        // a socket bound to `::` sees IPv4 peers as IPv4-mapped addresses
+        self.addresses.iter().any(|address| {
+            address.port() == addr.port() && address.ip().to_canonical() == addr.ip().to_canonical()
+        })
+    }
+}
+
+impl Replicator {
+    pub fn new(address: &str, peers: &[String]) -> std::io::Result<Self> {
+        Self::with_socket(UdpSocket::bind(address)?, peers)
+    }
+
+    fn with_socket(socket: UdpSocket, peers: &[String]) -> std::io::Result<Self> {
+        let local = socket.local_addr()?;
+        let peers = peers
+            .iter()
+            .map(|peer| {
+                let addresses = peer.to_socket_addrs()?.collect::<Vec<_>>();
+                let address = addresses
+                    .iter()
+                    .find(|address| address.is_ipv4() == local.is_ipv4())
+                    .or(addresses.first())
+                    .copied()
+                    .ok_or_else(|| std::io::Error::other(format!("{} has no address", peer)))?;
+                Ok(Peer {
+                    addresses,
+                    address,
+                    received: AtomicU64::new(0),
+                    synced: AtomicBool::new(false),
+                })
+            })
+            .collect::<std::io::Result<_>>()?;
+        Ok(Self {
+            socket,
+            peers,
+            node: RandomState::new().hash_one(local),
+            clock: AtomicU64::new(0),
+        })
+    }
+
+    // version for an insert received from a client
+    pub fn tick(&self) -> Version {
+        Version {
+            clock: self.clock.fetch_add(1, Ordering::SeqCst) + 1,
+            node: self.node,
+        }
+    }
+
+    pub fn forward(&self, key: &[u8], value: &[u8], version: Version) {
+        let update = encode_update(b'U', key, value, version);
+        for peer in &self.peers {
+            if let Err(e) = self.socket.send_to(&update, peer.address) {
+                eprintln!("Error forwarding insert to {}: {:?}", peer.address, e);
+            }
+        }
+    }
+
+    // anti-entropy on startup, peers answer with everything they hold. Asks again every
+    // RESYNC_INTERVAL until each peer has answered with no entry missing, as any datagram may be
+    // lost, or the peer may not be up yet. Returns once all have.
+    pub fn resync(&self) {
+        loop {
+            let mut pending = 0;
+            for peer in self
+                .peers
+                .iter()
+                .filter(|peer| !peer.synced.load(Ordering::SeqCst))
+            {
+                pending += 1;
+                peer.received.store(0, Ordering::SeqCst);
+                if let Err(e) = self.socket.send_to(b"R", peer.address) {
+                    eprintln!("Error requesting resync from {}: {:?}", peer.address, e);
+                }
+            }
+            if pending == 0 {
+                eprintln!("Resynced with all peers");
+                return;
+            }
+            std::thread::sleep(RESYNC_INTERVAL);
+        }
+    }
+
+    pub fn serve(&self, storage: &Sharded) {
+        loop {
+            let mut buf = [0; MAX_DATAGRAM_SIZE + 32];
+            let (size, addr) = match self.socket.recv_from(&mut buf) {
+                Ok(x) => x,
+                Err(e) => {
+                    eprintln!("Error receiving replication datagram: {:?}", e);
+                    continue;
+                }
+            };
+            let Some(peer) = self.peers.iter().find(|peer| peer.is(addr)) else {
+                eprintln!("Ignoring replication datagram from unknown peer {}", addr);
+                continue;
+            };
+            match &buf[..size] {
+                [tag @ (b'U' | b'S'), update @ ..] => {
+                    let Some((key, value, version)) = decode_update(update) else {
+                        eprintln!("Dropping malformed update from {}", addr);
+                        continue;
+                    };
+                    if *tag == b'S' {
+                        peer.received.fetch_add(1, Ordering::SeqCst);
+                    }
+                    self.clock.fetch_max(version.clock, Ordering::SeqCst);
+                    let mut shard = storage.shard(key);
+                    if shard.version(key).is_none_or(|current| version > current) {
+                        shard.insert_with_ttl(key.to_vec(), value.to_vec(), None, version);
+                    }
+                }
+                b"R" => {
+                    let mut sent = 0;
+                    for mut shard in storage.shards() {
+                        let entries = shard.entries();
+                        let updates = entries
+                            .iter()
+                            .map(|(key, value)| {
+                                let version = shard.version(key).unwrap_or_default();
+                                encode_update(b'S', key, value, version)
+                            })
+                            .collect::<Vec<_>>();
+                        drop(shard);
+                        for update in updates {
+                            if let Err(e) = self.socket.send_to(&update, addr) {
+                                eprintln!("Error sending resync to {}: {:?}", addr, e);
+                            }
+                            sent += 1;
+                        }
+                    }
+                    let done = [&b"D"[..], &(sent as u64).to_be_bytes()].concat();
+                    if let Err(e) = self.socket.send_to(&done, addr) {
+                        eprintln!("Error sending resync to {}: {:?}", addr, e);
+                    }
+                    eprintln!("Sent {} entries to {} for resync", sent, addr);
+                }
+                [b'D', count @ ..] => {
+                    let Ok(count) = count.try_into().map(u64::from_be_bytes) else {
+                        eprintln!("Dropping malformed resync end from {}", addr);
+                        continue;
+                    };
+                    let received = peer.received.load(Ordering::SeqCst);
+                    if received >= count {
+                        peer.synced.store(true, Ordering::SeqCst);
+                    } else {
+                        eprintln!(
+                            "Resync from {} lost {} of {} entries, retrying",
+                            addr,
+                            count - received,
+                            count
+                        );
+                    }
+                }
+                _ => eprintln!("Dropping unknown replication datagram from {}", addr),
+            }
+        }
+    }
+}
+
+fn encode_update(tag: u8, key: &[u8], value: &[u8], version: Version) -> Vec<u8> {
+    [
+        &[tag][..],
+        &version.clock.to_be_bytes()[..],
+        &version.node.to_be_bytes(),
+        &(key.len() as u16).to_be_bytes(),
+        key,
+        value,
+    ]
+    .concat()
+}
+
+fn decode_update(update: &[u8]) -> Option<(&[u8], &[u8], Version)> {
+    let (clock, rest) = update.split_first_chunk::<8>()?;
+    let (node, rest) = rest.split_first_chunk::<8>()?;
+    let (key_length, rest) = rest.split_first_chunk::<2>()?;
+    let key_length = u16::from_be_bytes(*key_length) as usize;
+    if rest.len() < key_length || rest.len() >= MAX_DATAGRAM_SIZE {
+        return None;
+    }
+    let version = Version {
+        clock: u64::from_be_bytes(*clock),
+        node: u64::from_be_bytes(*node),
+    };
+    Some((&rest[..key_length], &rest[key_length..], version))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        sync::Arc,
+        time::{Duration, Instant},
+    };
+
+    use super::*;
+    use crate::p04::{
+        bounded::{Bounded, Limits},
+        storage::MemoryStorage,
+    };
+
+    struct Instance {
+        storage: Arc<Sharded>,
+        replicator: Arc<Replicator>,
+    }
+
+    impl Instance {
+        // peers are named `localhost`, which may resolve to `::1` as well as `127.0.0.1`
+        fn start(socket: UdpSocket, peers: &[u16]) -> Self {
+            let shards = (0..4)
+                .map(|_| {
+                    let limits = Limits {
+                        max_entries: None,
+                        max_bytes: None,
+                    };
+                    Bounded::new(Box::new(MemoryStorage::default()), limits)
+                })
+                .collect();
+            let storage = Arc::new(Sharded::new(shards));
+            let peers = peers
+                .iter()
+                .map(|port| format!("localhost:{}", port))
+                .collect::<Vec<_>>();
+            let replicator = Arc::new(Replicator::with_socket(socket, &peers).unwrap());
+            let (replicator2, storage2) = (replicator.clone(), storage.clone());
+            std::thread::spawn(move || replicator2.serve(&storage2));
+            let replicator2 = replicator.clone();
+            std::thread::spawn(move || replicator2.resync());
+            Self {
+                storage,
+                replicator,
+            }
+        }
+
+        fn insert(&self, key: &str, value: &str) {
+            let version = self.replicator.tick();
+            let mut shard = self.storage.shard(key.as_bytes());
+            shard.insert_with_ttl(key.into(), value.into(), None, version);
+            drop(shard);
+            self.replicator
+                .forward(key.as_bytes(), value.as_bytes(), version);
+        }
+
+        fn get(&self, key: &str) -> Option<Vec<u8>> {
+            self.storage.shard(key.as_bytes()).get(key.as_bytes())
+        }
+
+        fn synced(&self) -> bool {
+            let peers = &self.replicator.peers;
+            peers.iter().all(|peer| peer.synced.load(Ordering::SeqCst))
+        }
+    }
+
+    fn eventually(condition: impl Fn() -> bool) -> bool {
+        let deadline = Instant::now() + Duration::from_secs(10);
+        while Instant::now() < deadline {
+            if condition() {
+                return true;
+            }
+            std::thread::sleep(Duration::from_millis(20));
+        }
+        false
+    }
+
+    fn bind() -> (UdpSocket, u16) {
+        let socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let port = socket.local_addr().unwrap().port();
+        (socket, port)
+    }
+
+    #[test]
+    fn peer_addresses() {
+        let socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let peers = ["[::1]:7000".to_owned(), "127.0.0.1:7001".to_owned()];
+        let replicator = Replicator::with_socket(socket, &peers).unwrap();
+        let [v6, v4] = &replicator.peers[..] else {
+            panic!()
+        };
+        assert!(v6.is("[::1]:7000".parse().unwrap()));
+        assert!(v4.is("127.0.0.1:7001".parse().unwrap()));
+        assert!(v4.is("[::ffff:127.0.0.1]:7001".parse().unwrap()));
+        assert!(!v4.is("127.0.0.1:7000".parse().unwrap()));
+        assert!(!v4.is("127.0.0.2:7001".parse().unwrap()));
+    }
+
+    #[test]
+    fn instances_converge() {
+        let (a, a_port) = bind();
+        // b isn't listening until after a has asked it for a resync
+        let (b, b_port) = bind();
+        drop(b);
+        let a = Instance::start(a, &[b_port]);
+        a.insert("early", "a");
+        std::thread::sleep(RESYNC_INTERVAL / 2);
+        assert!(!a.synced());
+
+        let b = UdpSocket::bind(("127.0.0.1", b_port)).unwrap();
+        let b = Instance::start(b, &[a_port]);
+        b.insert("late", "b");
+        assert!(eventually(|| a.synced() && b.synced()));
+        assert!(eventually(|| b.get("early") == Some(b"a".to_vec())));
+        assert!(eventually(|| a.get("late") == Some(b"b".to_vec())));
+
+        // inserts are forwarded, and the later version wins on both
+        a.insert("key", "1");
+        assert!(eventually(|| b.get("key") == Some(b"1".to_vec())));
+        b.insert("key", "2");
+        assert!(eventually(|| a.get("key") == Some(b"2".to_vec())));
+        assert_eq!(b.get("key"), Some(b"2".to_vec()));
+    }
+
+    #[test]
+    fn lossy_resync_is_retried() {
+        let (peer, peer_port) = bind();
+        peer.set_read_timeout(Some(Duration::from_secs(10)))
+            .unwrap();
+        let (socket, _) = bind();
+        let a = Instance::start(socket, &[peer_port]);
+        let mut buffer = [0; MAX_DATAGRAM_SIZE];
+
+        // the peer claims two entries but only one arrives
+        let (_, addr) = peer.recv_from(&mut buffer).unwrap();
+        assert_eq!(&buffer[..1], b"R");
+        let entry = encode_update(b'S', b"key", b"value", Version::default());
+        peer.send_to(&entry, addr).unwrap();
+        peer.send_to(&[&b"D"[..], &2u64.to_be_bytes()].concat(), addr)
+            .unwrap();
+        let (_, _) = peer.recv_from(&mut buffer).unwrap();
+        assert_eq!(&buffer[..1], b"R");
+        assert!(!a.synced());
+
+        peer.send_to(&entry, addr).unwrap();
+        peer.send_to(&[&b"D"[..], &1u64.to_be_bytes()].concat(), addr)
+            .unwrap();
+        assert!(eventually(|| a.synced()));
+        assert_eq!(a.get("key"), Some(b"value".to_vec()));
+    }
+}
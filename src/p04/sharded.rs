@@ -0,0 +1,103 @@
+use std::sync::{Mutex, MutexGuard};
+
+use super::{bounded::Bounded, storage::fnv1a};
+
+// Keys spread over independently locked stores, so that workers only contend on the same shard.
+// The hash is stable across restarts, so a key recovered from a shard's log stays in that shard.
+// A worker that panicked while holding a shard doesn't take the shard down with it, the next one
+// to lock it rebuilds its bookkeeping.
+pub struct Sharded(Vec<Mutex<Bounded>>);
+
+impl Sharded {
+    pub fn new(shards: Vec<Bounded>) -> Self {
+        Self(shards.into_iter().map(Mutex::new).collect())
+    }
+
+    pub fn shard(&self, key: &[u8]) -> MutexGuard<'_, Bounded> {
+        let index = fnv1a(key) as usize % self.0.len();
+        lock(&self.0[index])
+    }
+
+    // locks each shard in turn
+    pub fn shards(&self) -> impl Iterator<Item = MutexGuard<'_, Bounded>> {
+        self.0.iter().map(lock)
+    }
+}
+
+fn lock(shard: &Mutex<Bounded>) -> MutexGuard<'_, Bounded> {
+    shard.lock().unwrap_or_else(|e| {
+        let mut bounded = e.into_inner();
+        eprintln!("Rebuilding a shard after a panic");
+        bounded.rebuild();
+        shard.clear_poison();
+        bounded
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::p04::{
+        bounded::Limits,
+        replication::Version,
+        storage::{MemoryStorage, Storage},
+    };
+
+    // panics after storing `boom`, before the bookkeeping has caught up
+    #[derive(Default)]
+    struct Panicky(MemoryStorage);
+
+    impl Storage for Panicky {
+        fn get(&mut self, key: &[u8]) -> Option<Vec<u8>> {
+            self.0.get(key)
+        }
+
+        fn insert(&mut self, key: Vec<u8>, value: Vec<u8>) {
+            let boom = key == b"boom";
+            self.0.insert(key, value);
+            if boom {
+                panic!("while inserting");
+            }
+        }
+
+        fn remove(&mut self, key: &[u8]) {
+            self.0.remove(key);
+        }
+
+        fn entries(&self) -> Vec<(Vec<u8>, Vec<u8>)> {
+            self.0.entries()
+        }
+    }
+
+    #[test]
+    fn rebuilds_after_panic() {
+        let limits = Limits {
+            max_entries: Some(2),
+            max_bytes: None,
+        };
+        let shard = Bounded::new(Box::new(Panicky::default()), limits);
+        let sharded = Sharded::new(vec![shard]);
+        sharded.shard(b"a").insert(b"a".to_vec(), b"1".to_vec());
+        let res = std::thread::scope(|scope| {
+            scope
+                .spawn(|| {
+                    let mut shard = sharded.shard(b"boom");
+                    shard.insert_with_ttl(b"boom".to_vec(), b"!".to_vec(), None, Version::default())
+                })
+                .join()
+        });
+        assert!(res.is_err());
+
+        let mut shard = sharded.shard(b"boom");
+        let mut keys = shard.keys(b"");
+        keys.sort();
+        assert_eq!(keys, [b"a".to_vec(), b"boom".to_vec()]);
+        assert_eq!(shard.usage(b""), (2, 7));
+        // eviction sees the key it missed
+        shard.insert(b"c".to_vec(), b"3".to_vec());
+        assert_eq!(shard.usage(b"").0, 2);
+        assert_eq!(shard.entries().len(), 2);
+        drop(shard);
+        assert!(!sharded.0[0].is_poisoned());
+    }
+}
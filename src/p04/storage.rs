@@ -0,0 +1,568 @@
+use std::{
+    collections::HashMap,
+    fs::{File, OpenOptions},
+    io::{BufReader, ErrorKind, Read, Seek, SeekFrom, Write},
+    path::Path,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, Mutex, Weak,
+    },
+    time::Duration,
+};
+
+pub trait Storage: Send {
+    fn get(&mut self, key: &[u8]) -> Option<Vec<u8>>;
+    fn insert(&mut self, key: Vec<u8>, value: Vec<u8>);
+    fn remove(&mut self, key: &[u8]);
+    fn entries(&self) -> Vec<(Vec<u8>, Vec<u8>)>;
+}
+
+#[derive(Default)]
+pub struct MemoryStorage(HashMap<Vec<u8>, Vec<u8>>);
+
+impl Storage for MemoryStorage {
+    fn get(&mut self, key: &[u8]) -> Option<Vec<u8>> {
+        self.0.get(key).cloned()
+    }
+
+    fn insert(&mut self, key: Vec<u8>, value: Vec<u8>) {
+        self.0.insert(key, value);
+    }
+
+    fn remove(&mut self, key: &[u8]) {
+        self.0.remove(key);
+    }
+
+    fn entries(&self) -> Vec<(Vec<u8>, Vec<u8>)> {
+        self.0.clone().into_iter().collect()
+    }
+}
+
+#[allow(dead_code)]
+#[derive(Clone, Copy)]
+pub enum FsyncPolicy {
+    EveryWrite,
+    // by a background thread, once per interval in which anything was written
+    Interval(Duration),
+    // leave it to the OS
+    Never,
+}
+
+// The log is compacted once it holds this many times more records than there are live keys
+const COMPACTION_RATIO: usize = 4;
+const MIN_RECORDS_BEFORE_COMPACTION: usize = 1024;
+
+// In-memory map backed by an append-only log of inserts and removals. Each record is
+// `<key length u32><value length u32><key><value><FNV-1a checksum u32>`, all big-endian, with a
+// value length of u32::MAX and no value marking a removal. On startup, the log is replayed up to
+// the first incomplete or corrupt record (a write interrupted by a crash) and truncated there.
+pub struct LogStorage {
+    map: HashMap<Vec<u8>, Vec<u8>>,
+    path: String,
+    log: Arc<Log>,
+    fsync: FsyncPolicy,
+    records: usize,
+}
+
+// shared with the thread syncing it for FsyncPolicy::Interval
+struct Log {
+    file: Mutex<File>,
+    // written to since the last sync
+    dirty: AtomicBool,
+}
+
+impl LogStorage {
+    pub fn open(path: &str, fsync: FsyncPolicy) -> std::io::Result<Self> {
+        let mut file = OpenOptions::new()
+            .create(true)
+            .read(true)
+            .append(true)
+            .open(path)?;
+        let mut map = HashMap::new();
+        let mut records = 0;
+        let mut valid_length = 0;
+        let mut reader = BufReader::new(&mut file);
+        while let Some((key, value)) = read_record(&mut reader)? {
+            valid_length += record_length(&key, value.as_deref());
+            records += 1;
+            match value {
+                Some(value) => map.insert(key, value),
+                None => map.remove(&key),
+            };
+        }
+        if valid_length < file.metadata()?.len() {
+            eprintln!(
+                "Truncating incomplete record at the end of {} (offset {})",
+                path, valid_length
+            );
+            file.set_len(valid_length)?;
+            file.sync_data()?;
+        }
+        file.seek(SeekFrom::End(0))?;
+        eprintln!(
+            "Recovered {} keys from {} records in {}",
+            map.len(),
+            records,
+            path
+        );
+        let log = Arc::new(Log {
+            file: Mutex::new(file),
+            dirty: AtomicBool::new(false),
+        });
+        if let FsyncPolicy::Interval(interval) = fsync {
+            let (log, path) = (Arc::downgrade(&log), path.to_owned());
+            std::thread::spawn(move || sync_periodically(log, &path, interval));
+        }
+        Ok(Self {
+            map,
+            path: path.to_owned(),
+            log,
+            fsync,
+            records,
+        })
+    }
+
+    fn append(&mut self, key: &[u8], value: Option<&[u8]>) -> std::io::Result<()> {
+        let mut file = self.log.file.lock().unwrap();
+        file.write_all(&encode_record(key, value))?;
+        self.records += 1;
+        match self.fsync {
+            FsyncPolicy::EveryWrite => file.sync_data()?,
+            FsyncPolicy::Interval(_) => self.log.dirty.store(true, Ordering::SeqCst),
+            FsyncPolicy::Never => {}
+        }
+        Ok(())
+    }
+
+    fn sync(&self) -> std::io::Result<()> {
+        self.log.file.lock().unwrap().sync_data()
+    }
+
+    fn maybe_compact(&mut self) {
+        if self.records >= MIN_RECORDS_BEFORE_COMPACTION
+            && self.records > self.map.len() * COMPACTION_RATIO
+            && let Err(e) = self.compact()
+        {
+            eprintln!("Error compacting {}: {:?}", self.path, e);
+        }
+    }
+
+    // writes the live entries to a new file, which then atomically replaces the log
+    fn compact(&mut self) -> std::io::Result<()> {
+        let tmp_path = format!("{}.compact", self.path);
+        let mut tmp = File::create(&tmp_path)?;
+        let mut data = Vec::new();
+        for (key, value) in &self.map {
+            data.extend_from_slice(&encode_record(key, Some(value)));
+        }
+        tmp.write_all(&data)?;
+        tmp.sync_all()?;
+        let mut file = self.log.file.lock().unwrap();
+        std::fs::rename(&tmp_path, &self.path)?;
+        // the rename itself is only durable once the directory is synced
+        let dir = Path::new(&self.path)
+            .parent()
+            .filter(|dir| !dir.as_os_str().is_empty())
+            .unwrap_or(Path::new("."));
+        File::open(dir)?.sync_all()?;
+        *file = OpenOptions::new().append(true).open(&self.path)?;
+        drop(file);
+        eprintln!(
+            "Compacted {} from {} to {} records",
+            self.path,
+            self.records,
+            self.map.len()
+        );
+        self.records = self.map.len();
+        Ok(())
+    }
+}
+
+impl Storage for LogStorage {
+    fn get(&mut self, key: &[u8]) -> Option<Vec<u8>> {
+        self.map.get(key).cloned()
+    }
+
+    fn insert(&mut self, key: Vec<u8>, value: Vec<u8>) {
+        if let Err(e) = self.append(&key, Some(&value)) {
+            eprintln!("Error appending to {}: {:?}", self.path, e);
+        }
+        self.map.insert(key, value);
+        self.maybe_compact();
+    }
+
+    fn remove(&mut self, key: &[u8]) {
+        if self.map.remove(key).is_none() {
+            return;
+        }
+        if let Err(e) = self.append(key, None) {
+            eprintln!("Error appending to {}: {:?}", self.path, e);
+        }
+        self.maybe_compact();
+    }
+
+    fn entries(&self) -> Vec<(Vec<u8>, Vec<u8>)> {
+        self.map.clone().into_iter().collect()
+    }
+}
+
+// Opens a log per shard, at `<path>.<shard>`, or at `<path>` for a single shard. Shards are chosen
+// by `fnv1a(key) % shards`, so when the number of shards changed since the logs were written,
+// recovered keys are moved to the shard they now belong to, and the logs of shards that no longer
+// exist are merged into the others and deleted.
+pub fn open_sharded(
+    path: &str,
+    shards: usize,
+    fsync: FsyncPolicy,
+) -> std::io::Result<Vec<LogStorage>> {
+    let name = |i: usize| {
+        if shards == 1 {
+            path.to_owned()
+        } else {
+            format!("{}.{}", path, i)
+        }
+    };
+    let mut logs = (0..shards)
+        .map(|i| LogStorage::open(&name(i), fsync))
+        .collect::<std::io::Result<Vec<_>>>()?;
+    let mut moved = 0;
+    for i in 0..shards {
+        for (key, value) in logs[i].entries() {
+            let shard = fnv1a(&key) as usize % shards;
+            if shard != i {
+                // added before it is removed, so a crash in between loses nothing
+                logs[shard].insert(key.clone(), value);
+                logs[shard].sync()?;
+                logs[i].remove(&key);
+                moved += 1;
+            }
+        }
+    }
+
+    let path = Path::new(path);
+    let dir = path
+        .parent()
+        .filter(|dir| !dir.as_os_str().is_empty())
+        .unwrap_or(Path::new("."));
+    let base = path.file_name().unwrap_or_default().to_string_lossy();
+    for file in std::fs::read_dir(dir)? {
+        let file_name = file?.file_name();
+        let file_name = file_name.to_string_lossy();
+        let Some(suffix) = file_name.strip_prefix(&*base) else { continue; };
+        let current = match suffix.strip_prefix('.') {
+            None if suffix.is_empty() => shards == 1,
+            Some(i) if !i.is_empty() && i.bytes().all(|b| b.is_ascii_digit()) => {
+                shards > 1 && i.parse().is_ok_and(|i: usize| i < shards)
+            }
+            _ => continue,
+        };
+        if current {
+            continue;
+        }
+        let stale = dir.join(&*file_name).to_string_lossy().into_owned();
+        let log = LogStorage::open(&stale, FsyncPolicy::Never)?;
+        for (key, value) in log.entries() {
+            logs[fnv1a(&key) as usize % shards].insert(key, value);
+            moved += 1;
+        }
+        drop(log);
+        for log in &logs {
+            log.sync()?;
+        }
+        std::fs::remove_file(&stale)?;
+        eprintln!(
+            "Merged and deleted {}, a log of a shard that no longer exists",
+            stale
+        );
+    }
+    if moved > 0 {
+        eprintln!("Moved {} keys to their shard", moved);
+    }
+    Ok(logs)
+}
+
+// until the storage is dropped
+fn sync_periodically(log: Weak<Log>, path: &str, interval: Duration) {
+    loop {
+        std::thread::sleep(interval);
+        let Some(log) = log.upgrade() else { return; };
+        if !log.dirty.swap(false, Ordering::SeqCst) {
+            continue;
+        }
+        // on a handle of its own, so that writes don't wait for the sync
+        let res = log
+            .file
+            .lock()
+            .unwrap()
+            .try_clone()
+            .and_then(|file| file.sync_data());
+        if let Err(e) = res {
+            eprintln!("Error syncing {}: {:?}", path, e);
+            log.dirty.store(true, Ordering::SeqCst);
+        }
+    }
+}
+
+pub fn fnv1a(data: &[u8]) -> u32 {
+    data.iter().fold(0x811c9dc5, |hash, byte| {
+        (hash ^ *byte as u32).wrapping_mul(0x01000193)
+    })
+}
+
+const TOMBSTONE: u32 = u32::MAX;
+
+fn record_length(key: &[u8], value: Option<&[u8]>) -> u64 {
+    (4 + 4 + key.len() + value.map_or(0, |v| v.len()) + 4) as u64
+}
+
+fn encode_record(key: &[u8], value: Option<&[u8]>) -> Vec<u8> {
+    let mut record = Vec::with_capacity(record_length(key, value) as usize);
+    record.extend_from_slice(&(key.len() as u32).to_be_bytes());
+    record.extend_from_slice(&value.map_or(TOMBSTONE, |v| v.len() as u32).to_be_bytes());
+    record.extend_from_slice(key);
+    record.extend_from_slice(value.unwrap_or_default());
+    record.extend_from_slice(&fnv1a(&record).to_be_bytes());
+    record
+}
+
+// None at the end of the log, or at an incomplete or corrupt record
+#[allow(clippy::type_complexity)]
+fn read_record(reader: &mut impl Read) -> std::io::Result<Option<(Vec<u8>, Option<Vec<u8>>)>> {
+    let mut lengths = [0; 8];
+    if !read_exact_or_eof(reader, &mut lengths)? {
+        return Ok(None);
+    }
+    let key_length = u32::from_be_bytes(lengths[..4].try_into().unwrap()) as usize;
+    let value_length = u32::from_be_bytes(lengths[4..].try_into().unwrap());
+    let removal = value_length == TOMBSTONE;
+    let value_length = if removal { 0 } else { value_length as usize };
+    // no valid request can hold a larger record
+    if key_length + value_length >= super::MAX_DATAGRAM_SIZE {
+        return Ok(None);
+    }
+    let mut rest = vec![0; key_length + value_length + 4];
+    if !read_exact_or_eof(reader, &mut rest)? {
+        return Ok(None);
+    }
+    let (data, stored_checksum) = rest.split_at(key_length + value_length);
+    let mut record = lengths.to_vec();
+    record.extend_from_slice(data);
+    if fnv1a(&record).to_be_bytes() != stored_checksum {
+        return Ok(None);
+    }
+    let value = (!removal).then(|| data[key_length..].to_vec());
+    Ok(Some((data[..key_length].to_vec(), value)))
+}
+
+fn read_exact_or_eof(reader: &mut impl Read, buf: &mut [u8]) -> std::io::Result<bool> {
+    match reader.read_exact(buf) {
+        Ok(()) => Ok(true),
+        Err(e) if e.kind() == ErrorKind::UnexpectedEof => Ok(false),
+        Err(e) => Err(e),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // a fresh directory per test
+    fn dir(name: &str) -> String {
+        let dir = std::env::temp_dir().join(format!("p04-{}-{}", name, std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        dir.join("log").to_str().unwrap().to_owned()
+    }
+
+    fn sorted(storage: &LogStorage) -> Vec<(Vec<u8>, Vec<u8>)> {
+        let mut entries = storage.entries();
+        entries.sort();
+        entries
+    }
+
+    fn entry(key: &str, value: &str) -> (Vec<u8>, Vec<u8>) {
+        (key.into(), value.into())
+    }
+
+    #[test]
+    fn records_round_trip() {
+        let mut data = Vec::new();
+        data.extend(encode_record(b"key", Some(b"value")));
+        data.extend(encode_record(b"", Some(b"")));
+        data.extend(encode_record(b"key", None));
+        let mut reader = &data[..];
+        let key = b"key".to_vec();
+        assert_eq!(
+            read_record(&mut reader).unwrap(),
+            Some((key.clone(), Some(b"value".to_vec())))
+        );
+        assert_eq!(
+            read_record(&mut reader).unwrap(),
+            Some((vec![], Some(vec![])))
+        );
+        assert_eq!(read_record(&mut reader).unwrap(), Some((key, None)));
+        assert_eq!(read_record(&mut reader).unwrap(), None);
+    }
+
+    #[test]
+    fn tombstones_survive_reopening() {
+        let path = dir("tombstones");
+        let mut storage = LogStorage::open(&path, FsyncPolicy::EveryWrite).unwrap();
+        storage.insert(b"a".to_vec(), b"1".to_vec());
+        storage.insert(b"b".to_vec(), b"2".to_vec());
+        storage.remove(b"a");
+        storage.insert(b"b".to_vec(), b"3".to_vec());
+        drop(storage);
+        let storage = LogStorage::open(&path, FsyncPolicy::EveryWrite).unwrap();
+        assert_eq!(sorted(&storage), [entry("b", "3")]);
+        assert_eq!(storage.records, 4);
+    }
+
+    #[test]
+    fn torn_record_is_truncated() {
+        let path = dir("torn");
+        let mut storage = LogStorage::open(&path, FsyncPolicy::Never).unwrap();
+        storage.insert(b"a".to_vec(), b"1".to_vec());
+        drop(storage);
+        let complete = std::fs::metadata(&path).unwrap().len();
+        // a crash in the middle of appending the second record
+        let record = encode_record(b"b", Some(b"2"));
+        let mut file = OpenOptions::new().append(true).open(&path).unwrap();
+        file.write_all(&record[..record.len() - 3]).unwrap();
+        drop(file);
+
+        let mut storage = LogStorage::open(&path, FsyncPolicy::Never).unwrap();
+        assert_eq!(sorted(&storage), [entry("a", "1")]);
+        assert_eq!(std::fs::metadata(&path).unwrap().len(), complete);
+        // and later appends land after the last complete record
+        storage.insert(b"c".to_vec(), b"3".to_vec());
+        drop(storage);
+        let storage = LogStorage::open(&path, FsyncPolicy::Never).unwrap();
+        assert_eq!(sorted(&storage), [entry("a", "1"), entry("c", "3")]);
+    }
+
+    #[test]
+    fn bad_checksum_ends_recovery() {
+        let path = dir("checksum");
+        let mut data = encode_record(b"a", Some(b"1"));
+        let mut corrupt = encode_record(b"b", Some(b"2"));
+        let last = corrupt.len() - 1;
+        corrupt[last] ^= 1;
+        data.extend(corrupt);
+        data.extend(encode_record(b"c", Some(b"3")));
+        std::fs::write(&path, &data).unwrap();
+
+        let storage = LogStorage::open(&path, FsyncPolicy::Never).unwrap();
+        assert_eq!(sorted(&storage), [entry("a", "1")]);
+        let expected = record_length(b"a", Some(b"1"));
+        assert_eq!(std::fs::metadata(&path).unwrap().len(), expected);
+    }
+
+    #[test]
+    fn compaction_round_trip() {
+        let path = dir("compaction");
+        let mut storage = LogStorage::open(&path, FsyncPolicy::Never).unwrap();
+        for i in 0..MIN_RECORDS_BEFORE_COMPACTION {
+            storage.insert(b"counter".to_vec(), i.to_string().into_bytes());
+        }
+        storage.insert(b"gone".to_vec(), b"soon".to_vec());
+        storage.remove(b"gone");
+        storage.insert(b"kept".to_vec(), b"yes".to_vec());
+        // compacted as soon as there were 4 times more records than keys
+        assert!(storage.records < 10);
+        let expected = [
+            entry("counter", &(MIN_RECORDS_BEFORE_COMPACTION - 1).to_string()),
+            entry("kept", "yes"),
+        ];
+        assert_eq!(sorted(&storage), expected);
+        drop(storage);
+
+        let storage = LogStorage::open(&path, FsyncPolicy::Never).unwrap();
+        assert_eq!(sorted(&storage), expected);
+        assert!(std::fs::metadata(format!("{}.compact", path)).is_err());
+    }
+
+    #[test]
+    fn interval_sync_while_idle() {
+        let path = dir("interval");
+        let interval = Duration::from_millis(20);
+        let mut storage = LogStorage::open(&path, FsyncPolicy::Interval(interval)).unwrap();
+        storage.insert(b"a".to_vec(), b"1".to_vec());
+        assert!(storage.log.dirty.load(Ordering::SeqCst));
+        // synced by the background thread, without another write
+        std::thread::sleep(interval * 5);
+        assert!(!storage.log.dirty.load(Ordering::SeqCst));
+    }
+
+    fn shard_keys(logs: &[LogStorage]) -> Vec<Vec<String>> {
+        logs.iter()
+            .map(|log| {
+                let mut keys = log
+                    .entries()
+                    .into_iter()
+                    .map(|(key, _)| String::from_utf8(key).unwrap())
+                    .collect::<Vec<_>>();
+                keys.sort();
+                keys
+            })
+            .collect()
+    }
+
+    fn check_routing(logs: &[LogStorage]) {
+        for (i, keys) in shard_keys(logs).iter().enumerate() {
+            for key in keys {
+                assert_eq!(fnv1a(key.as_bytes()) as usize % logs.len(), i, "{}", key);
+            }
+        }
+    }
+
+    #[test]
+    fn reshards_on_open() {
+        let path = dir("reshard");
+        let keys = (0..50).map(|i| format!("key{}", i)).collect::<Vec<_>>();
+        let mut logs = open_sharded(&path, 2, FsyncPolicy::Never).unwrap();
+        for key in &keys {
+            logs[fnv1a(key.as_bytes()) as usize % 2].insert(key.clone().into(), b"v".to_vec());
+        }
+        drop(logs);
+
+        for shards in [3, 1, 4] {
+            let logs = open_sharded(&path, shards, FsyncPolicy::Never).unwrap();
+            check_routing(&logs);
+            let mut all = shard_keys(&logs).concat();
+            all.sort();
+            let mut expected = keys.clone();
+            expected.sort();
+            assert_eq!(all, expected, "{} shards", shards);
+            drop(logs);
+            // only the current shards' logs are left
+            let mut files = std::fs::read_dir(Path::new(&path).parent().unwrap())
+                .unwrap()
+                .map(|file| file.unwrap().file_name().into_string().unwrap())
+                .collect::<Vec<_>>();
+            files.sort();
+            let mut expected = (0..shards)
+                .map(|i| format!("log.{}", i))
+                .collect::<Vec<_>>();
+            if shards == 1 {
+                expected = vec!["log".to_owned()];
+            }
+            assert_eq!(files, expected);
+        }
+    }
+
+    #[test]
+    fn migrates_single_log() {
+        let path = dir("legacy");
+        let mut log = LogStorage::open(&path, FsyncPolicy::Never).unwrap();
+        log.insert(b"a".to_vec(), b"1".to_vec());
+        log.insert(b"b".to_vec(), b"2".to_vec());
+        log.remove(b"a");
+        drop(log);
+
+        let logs = open_sharded(&path, 4, FsyncPolicy::Never).unwrap();
+        check_routing(&logs);
+        assert_eq!(shard_keys(&logs).concat(), ["b"]);
+        assert!(std::fs::metadata(&path).is_err());
+    }
+}
@@ -0,0 +1,539 @@
+use std::{
+    io::{BufRead, BufReader, ErrorKind, Read, Write},
+    net::{SocketAddr, TcpListener, TcpStream, ToSocketAddrs},
+    sync::Arc,
+    time::Duration,
+};
+
+use super::recording::{escape, Recording};
+
+// Sessions whose length-prefixed frames announce more than this are closed
+const MAX_FRAME_LENGTH: usize = 1 << 20;
+const RAW_CHUNK_SIZE: usize = 4096;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Direction {
+    ClientToServer,
+    ServerToClient,
+}
+
+// How a stream is cut into the messages handed to the interceptor
+#[allow(dead_code)]
+#[derive(Clone, Copy)]
+pub enum Framing {
+    // up to a `\n`, which is not part of the message
+    Lines,
+    // a big-endian length of `header` (1 to 8) bytes, followed by that many bytes of message
+    LengthPrefixed { header: usize },
+    // whatever a single read returns
+    Raw,
+}
+
+#[allow(dead_code)]
+#[derive(Clone, Copy)]
+pub enum Unterminated {
+    Withhold,
+    // forwarded as is, without intercepting
+    PassThrough,
+}
+
+// Called for every message, in order per direction, but the two directions run concurrently
+pub trait Interceptor: Send + Sync {
+    // what to forward instead of `message`, without framing, or None to drop it
+    fn intercept(&self, session: usize, direction: Direction, message: Vec<u8>) -> Option<Vec<u8>>;
+
+    fn connected(&self, _session: usize, _client: SocketAddr) {}
+
+    // once per direction
+    fn disconnected(&self, _session: usize, _direction: Direction) {}
+}
+
+// Forwards messages unchanged, logging them
+pub struct Inspect;
+
+impl Interceptor for Inspect {
+    fn intercept(&self, session: usize, direction: Direction, message: Vec<u8>) -> Option<Vec<u8>> {
+        eprintln!("[{}] {:?}: {}", session, direction, escape(&message));
+        Some(message)
+    }
+}
+
+pub struct Proxy {
+    pub port: u16,
+    pub upstream: String,
+    pub connect_timeout: Duration,
+    pub framing: Framing,
+    // data after the last complete frame when a side disconnects
+    pub unterminated: Unterminated,
+    // record every session to a file in this directory, see recording.rs
+    pub recording_dir: Option<&'static str>,
+}
+
+impl Proxy {
+    pub fn run(self, interceptor: Arc<dyn Interceptor>) {
+        let listener = TcpListener::bind(("0.0.0.0", self.port)).unwrap();
+        self.serve(listener, interceptor);
+    }
+
+    // `port` is ignored in favour of `listener`
+    fn serve(self, listener: TcpListener, interceptor: Arc<dyn Interceptor>) {
+        let proxy = Arc::new(self);
+
+        for (i, incoming) in listener.into_incoming().enumerate() {
+            let stream = match incoming {
+                Err(e) => {
+                    eprintln!("Error accepting incoming stream: {:?}", e);
+                    continue;
+                }
+                Ok(x) => x,
+            };
+            let (proxy, interceptor) = (proxy.clone(), interceptor.clone());
+            // connecting happens off the accept loop, so a slow or unreachable upstream only
+            // affects this client
+            std::thread::spawn(move || proxy.session(i, stream, interceptor));
+        }
+    }
+
+    fn session(self: Arc<Self>, i: usize, stream: TcpStream, interceptor: Arc<dyn Interceptor>) {
+        let upstream = match connect(&self.upstream, self.connect_timeout) {
+            Ok(x) => x,
+            Err(e) => {
+                eprintln!("[{}] Error connecting to {}: {:?}", i, self.upstream, e);
+                let _ = stream.shutdown(std::net::Shutdown::Both);
+                return;
+            }
+        };
+        let Ok(client) = stream.peer_addr() else { return; };
+        interceptor.connected(i, client);
+        let recording = self.recording_dir.and_then(|dir| {
+            Recording::create(dir, i, client)
+                .map_err(|e| eprintln!("[{}] Error creating recording: {:?}", i, e))
+                .ok()
+                .map(Arc::new)
+        });
+        let buffer = BufReader::new(stream.try_clone().unwrap());
+        let upstream_buffer = BufReader::new(upstream.try_clone().unwrap());
+        let (proxy, interceptor2, recording2) =
+            (self.clone(), interceptor.clone(), recording.clone());
+        std::thread::spawn(move || {
+            let direction = Direction::ServerToClient;
+            proxy.forward(
+                i,
+                direction,
+                upstream_buffer,
+                stream,
+                &*interceptor2,
+                recording2.as_deref(),
+            );
+            interceptor2.disconnected(i, direction);
+        });
+        let direction = Direction::ClientToServer;
+        self.forward(
+            i,
+            direction,
+            buffer,
+            upstream,
+            &*interceptor,
+            recording.as_deref(),
+        );
+        interceptor.disconnected(i, direction);
+    }
+
+    fn forward(
+        &self,
+        i: usize,
+        direction: Direction,
+        mut source: BufReader<TcpStream>,
+        mut dest: TcpStream,
+        interceptor: &dyn Interceptor,
+        recording: Option<&Recording>,
+    ) {
+        let hint = match direction {
+            Direction::ClientToServer => format!("[{}] client -> server", i),
+            Direction::ServerToClient => format!("[{}] server -> client", i),
+        };
+        loop {
+            let (original, output) = match read_frame(&mut source, self.framing) {
+                Ok(Frame::Complete(message)) => {
+                    let original = frame(&message, self.framing);
+                    let output = interceptor
+                        .intercept(i, direction, message)
+                        .map(|message| frame(&message, self.framing));
+                    (original, output)
+                }
+                // the source disconnected in the middle of a frame
+                Ok(Frame::Partial(data)) => match self.unterminated {
+                    Unterminated::Withhold => {
+                        eprintln!("Withholding unterminated data {}", hint);
+                        (data, None)
+                    }
+                    Unterminated::PassThrough => (data.clone(), Some(data)),
+                },
+                Ok(Frame::End) => {
+                    eprintln!("End of stream {}", hint);
+                    break;
+                }
+                Err(e) => {
+                    eprintln!("Error reading from stream {}: {:?}", hint, e);
+                    break;
+                }
+            };
+            if let Some(recording) = recording {
+                recording.record(direction, &original, output.as_deref());
+            }
+            let Some(output) = output else { continue; };
+            if output.len() > max_length(self.framing) {
+                eprintln!("Intercepted message too long for its frame {}", hint);
+                break;
+            }
+            if let Err(e) = dest.write_all(&output) {
+                eprintln!("Error writing to stream {}: {:?}", hint, e);
+                break;
+            }
+        }
+        let _ = source.get_ref().shutdown(std::net::Shutdown::Both);
+        let _ = dest.shutdown(std::net::Shutdown::Both);
+    }
+}
+
+// tries every address the upstream resolves to, each with `timeout`
+fn connect(address: &str, timeout: Duration) -> std::io::Result<TcpStream> {
+    let mut error = None;
+    for addr in address.to_socket_addrs()? {
+        match TcpStream::connect_timeout(&addr, timeout) {
+            Ok(stream) => return Ok(stream),
+            Err(e) => error = Some(e),
+        }
+    }
+    Err(error.unwrap_or_else(|| std::io::Error::other("no addresses to connect to")))
+}
+
+#[derive(Debug, PartialEq)]
+enum Frame {
+    Complete(Vec<u8>),
+    Partial(Vec<u8>),
+    End,
+}
+
+fn read_frame(source: &mut impl BufRead, framing: Framing) -> std::io::Result<Frame> {
+    match framing {
+        Framing::Lines => {
+            let mut data = Vec::new();
+            source.read_until(b'\n', &mut data)?;
+            Ok(match data.pop() {
+                None => Frame::End,
+                Some(b'\n') => Frame::Complete(data),
+                Some(last) => {
+                    data.push(last);
+                    Frame::Partial(data)
+                }
+            })
+        }
+        Framing::LengthPrefixed { header } => {
+            let mut data = vec![0; header];
+            let read = read_up_to(source, &mut data)?;
+            if read < header {
+                data.truncate(read);
+                return Ok(if read == 0 {
+                    Frame::End
+                } else {
+                    Frame::Partial(data)
+                });
+            }
+            let length = data.iter().fold(0, |length, b| length << 8 | *b as usize);
+            if length > MAX_FRAME_LENGTH {
+                return Err(std::io::Error::new(
+                    ErrorKind::InvalidData,
+                    format!("frame of {} bytes", length),
+                ));
+            }
+            let mut message = vec![0; length];
+            let read = read_up_to(source, &mut message)?;
+            if read < length {
+                data.extend_from_slice(&message[..read]);
+                return Ok(Frame::Partial(data));
+            }
+            Ok(Frame::Complete(message))
+        }
+        Framing::Raw => {
+            let mut data = vec![0; RAW_CHUNK_SIZE];
+            let read = source.read(&mut data)?;
+            data.truncate(read);
+            Ok(if read == 0 {
+                Frame::End
+            } else {
+                Frame::Complete(data)
+            })
+        }
+    }
+}
+
+// like `read_exact`, but returns how much was read before the end of the stream
+fn read_up_to(source: &mut impl Read, buf: &mut [u8]) -> std::io::Result<usize> {
+    let mut read = 0;
+    while read < buf.len() {
+        match source.read(&mut buf[read..]) {
+            Ok(0) => break,
+            Ok(n) => read += n,
+            Err(e) if e.kind() == ErrorKind::Interrupted => {}
+            Err(e) => return Err(e),
+        }
+    }
+    Ok(read)
+}
+
+fn frame(message: &[u8], framing: Framing) -> Vec<u8> {
+    match framing {
+        Framing::Lines => [message, b"\n"].concat(),
+        Framing::LengthPrefixed { header } => {
+            let length = (message.len() as u64).to_be_bytes();
+            [&length[8 - header..], message].concat()
+        }
+        Framing::Raw => message.to_vec(),
+    }
+}
+
+// the longest framed output that can be written
+fn max_length(framing: Framing) -> usize {
+    match framing {
+        Framing::LengthPrefixed { header } if header < 8 => header + (1 << (8 * header)) - 1,
+        _ => usize::MAX,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Mutex;
+
+    use super::*;
+
+    // records what it sees, and upper-cases what the client sends
+    #[derive(Default)]
+    struct Shout(Mutex<Vec<String>>);
+
+    impl Interceptor for Shout {
+        fn intercept(&self, _: usize, direction: Direction, message: Vec<u8>) -> Option<Vec<u8>> {
+            let message = String::from_utf8(message).unwrap();
+            self.0
+                .lock()
+                .unwrap()
+                .push(format!("{:?} {}", direction, message));
+            Some(match direction {
+                Direction::ClientToServer => message.to_uppercase().into_bytes(),
+                Direction::ServerToClient => message.into_bytes(),
+            })
+        }
+    }
+
+    fn proxy(
+        upstream: String,
+        unterminated: Unterminated,
+        interceptor: Arc<dyn Interceptor>,
+    ) -> SocketAddr {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap();
+        let proxy = Proxy {
+            port: 0,
+            upstream,
+            connect_timeout: Duration::from_secs(1),
+            framing: Framing::Lines,
+            unterminated,
+            recording_dir: None,
+        };
+        std::thread::spawn(move || proxy.serve(listener, interceptor));
+        address
+    }
+
+    // a stand-in for Budget Chat: greets, echoes each line back as `[you] <line>`, and answers
+    // `quit` with an unterminated `farewell` before hanging up. Returns everything it received.
+    fn upstream() -> (String, std::thread::JoinHandle<Vec<u8>>) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap().to_string();
+        let server = std::thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            let mut received = Vec::new();
+            let mut reader = BufReader::new(stream.try_clone().unwrap());
+            let _ = stream.write_all(b"Welcome, what's your name?\n");
+            loop {
+                let mut line = Vec::new();
+                if reader.read_until(b'\n', &mut line).unwrap_or(0) == 0 {
+                    break;
+                }
+                received.extend_from_slice(&line);
+                // the proxy may already have closed the session
+                let _ = stream.write_all(&[b"[you] ", &line[..]].concat());
+                if line == b"QUIT\n" {
+                    let _ = stream.write_all(b"farewell");
+                    break;
+                }
+            }
+            received
+        });
+        (address, server)
+    }
+
+    fn connect(address: SocketAddr) -> (TcpStream, BufReader<TcpStream>) {
+        let client = TcpStream::connect(address).unwrap();
+        client
+            .set_read_timeout(Some(Duration::from_secs(5)))
+            .unwrap();
+        let mut reader = BufReader::new(client.try_clone().unwrap());
+        let mut line = String::new();
+        reader.read_line(&mut line).unwrap();
+        assert_eq!(line, "Welcome, what's your name?\n");
+        (client, reader)
+    }
+
+    fn read_to_end(mut reader: BufReader<TcpStream>) -> String {
+        let mut received = String::new();
+        reader.read_to_string(&mut received).unwrap();
+        received
+    }
+
+    #[test]
+    fn forwards_through_interceptor() {
+        let (upstream, server) = upstream();
+        let interceptor = Arc::new(Shout::default());
+        let address = proxy(upstream, Unterminated::Withhold, interceptor.clone());
+
+        let (mut client, mut reader) = connect(address);
+        for (line, expected) in [
+            ("alice\n", "[you] ALICE\n"),
+            ("hi there\n", "[you] HI THERE\n"),
+        ] {
+            client.write_all(line.as_bytes()).unwrap();
+            let mut line = String::new();
+            reader.read_line(&mut line).unwrap();
+            assert_eq!(line, expected);
+        }
+        client.write_all(b"quit\n").unwrap();
+        // `farewell` is withheld
+        assert_eq!(read_to_end(reader), "[you] QUIT\n");
+        assert_eq!(server.join().unwrap(), b"ALICE\nHI THERE\nQUIT\n");
+        let seen = interceptor.0.lock().unwrap().clone();
+        assert_eq!(
+            seen[..4],
+            [
+                "ServerToClient Welcome, what's your name?",
+                "ClientToServer alice",
+                "ServerToClient [you] ALICE",
+                "ClientToServer hi there",
+            ]
+        );
+    }
+
+    #[test]
+    fn unterminated_from_server() {
+        let (upstream, _) = upstream();
+        let address = proxy(
+            upstream,
+            Unterminated::PassThrough,
+            Arc::new(Shout::default()),
+        );
+        let (mut client, reader) = connect(address);
+        client.write_all(b"quit\n").unwrap();
+        assert_eq!(read_to_end(reader), "[you] QUIT\nfarewell");
+    }
+
+    #[test]
+    fn unterminated_from_client() {
+        for (unterminated, expected) in [
+            (Unterminated::Withhold, &b"BOB\n"[..]),
+            (Unterminated::PassThrough, b"BOB\nbye"),
+        ] {
+            let (upstream, server) = upstream();
+            let address = proxy(upstream, unterminated, Arc::new(Shout::default()));
+            let (mut client, _) = connect(address);
+            client.write_all(b"bob\nbye").unwrap();
+            client.shutdown(std::net::Shutdown::Write).unwrap();
+            assert_eq!(server.join().unwrap(), expected);
+        }
+    }
+
+    #[test]
+    fn refused_upstream() {
+        // a port nothing listens on anymore
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let upstream = listener.local_addr().unwrap().to_string();
+        drop(listener);
+        let interceptor = Arc::new(Shout::default());
+        let address = proxy(upstream, Unterminated::Withhold, interceptor.clone());
+
+        // the client is disconnected, and the proxy keeps accepting others
+        for _ in 0..2 {
+            let client = TcpStream::connect(address).unwrap();
+            client
+                .set_read_timeout(Some(Duration::from_secs(5)))
+                .unwrap();
+            assert_eq!(read_to_end(BufReader::new(client)), "");
+        }
+        assert!(interceptor.0.lock().unwrap().is_empty());
+    }
+
+    fn frames(data: &[u8], framing: Framing) -> Vec<Frame> {
+        let mut source = data;
+        let mut frames = Vec::new();
+        loop {
+            match read_frame(&mut source, framing) {
+                Ok(Frame::End) => return frames,
+                Ok(frame) => frames.push(frame),
+                Err(e) => panic!("{:?} after {:?}", e, frames),
+            }
+        }
+    }
+
+    #[test]
+    fn read_lines() {
+        assert_eq!(
+            frames(b"a\n\nb c\xff\nd", Framing::Lines),
+            [
+                Frame::Complete(b"a".to_vec()),
+                Frame::Complete(b"".to_vec()),
+                Frame::Complete(b"b c\xff".to_vec()),
+                Frame::Partial(b"d".to_vec()),
+            ]
+        );
+        assert_eq!(frames(b"", Framing::Lines), []);
+    }
+
+    #[test]
+    fn read_length_prefixed() {
+        let framing = Framing::LengthPrefixed { header: 2 };
+        assert_eq!(
+            frames(b"\x00\x03abc\x00\x00\x00\x05ab", framing),
+            [
+                Frame::Complete(b"abc".to_vec()),
+                Frame::Complete(b"".to_vec()),
+                // the header is kept, so that the data can be passed through as is
+                Frame::Partial(b"\x00\x05ab".to_vec()),
+            ]
+        );
+        assert_eq!(frames(b"\x00", framing), [Frame::Partial(b"\x00".to_vec())]);
+        // one byte over MAX_FRAME_LENGTH
+        let e = read_frame(
+            &mut &b"\x10\x00\x01"[..],
+            Framing::LengthPrefixed { header: 3 },
+        );
+        assert_eq!(e.unwrap_err().kind(), ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn frame_round_trip() {
+        for framing in [
+            Framing::Lines,
+            Framing::LengthPrefixed { header: 1 },
+            Framing::LengthPrefixed { header: 8 },
+        ] {
+            let data = [frame(b"hello", framing), frame(b"", framing)].concat();
+            assert_eq!(
+                frames(&data, framing),
+                [
+                    Frame::Complete(b"hello".to_vec()),
+                    Frame::Complete(b"".to_vec())
+                ]
+            );
+        }
+        assert_eq!(max_length(Framing::LengthPrefixed { header: 1 }), 256);
+        assert_eq!(max_length(Framing::Lines), usize::MAX);
+    }
+}
@@ -1,68 +1,66 @@
-use std::{
-    io::{BufRead, BufReader, Write},
-    net::TcpStream,
-};
+mod mitm;
+mod recording;
+mod replay;
+mod rules;
 
-pub fn main() {
-    let listener = std::net::TcpListener::bind("0.0.0.0:1200").unwrap();
+use std::{sync::Arc, time::Duration};
 
-    for (i, incoming) in listener.into_incoming().enumerate() {
-        let stream = match incoming {
-            Err(e) => {
-                eprintln!("Error accepting incoming stream: {:?}", e);
-                continue;
-            }
-            Ok(x) => x,
-        };
-        let buffer = BufReader::new(stream.try_clone().unwrap());
-        let upstream = std::net::TcpStream::connect("206.189.113.124:16963").unwrap();
-        let upstream_buffer = BufReader::new(upstream.try_clone().unwrap());
-        std::thread::spawn(move || {
-            proxy(buffer, upstream, format!("[{}] client -> server", i));
-        });
-        std::thread::spawn(move || {
-            proxy(upstream_buffer, stream, format!("[{}] server -> client", i));
-        });
-    }
+use mitm::{Framing, Inspect, Interceptor, Proxy, Unterminated};
+use rules::Rules;
+
+const PORT: u16 = 1200;
+const UPSTREAM: &str = "206.189.113.124:16963";
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(5);
+// Budget Chat is line based, as are p09's job centre and p10's VCS
+const FRAMING: Framing = Framing::Lines;
+// Rewrite with the rules below, or only log the traffic
+const INTERCEPTOR: InterceptorKind = InterceptorKind::Rules;
+// Rewrite rules, see rules.rs. RULES_FILE replaces the built-in ones, which swap Boguscoin
+// addresses for Tony's
+const RULES: &str =
+    r#"[{"pattern": "7[a-zA-Z0-9]{25,34}", "replacement": "7YWHMfk9JZe0LM0g1ZauHuiSxhI"}]"#;
+const RULES_FILE: Option<&str> = None;
+// What to do with data after the last complete message when a side disconnects. The spec asks for
+// it to be withheld, since only complete messages may be forwarded
+const UNTERMINATED: Unterminated = Unterminated::Withhold;
+// Record every session to a file in this directory, see recording.rs
+const RECORDING_DIR: Option<&str> = None;
+
+#[allow(dead_code)]
+#[derive(Clone, Copy)]
+enum InterceptorKind {
+    Rules,
+    Inspect,
 }
 
-fn proxy(mut source: BufReader<TcpStream>, mut dest: TcpStream, hint: String) {
-    loop {
-        let mut msg = String::new();
-        let res = source.read_line(&mut msg);
-        match res {
-            Ok(0) => {
-                eprintln!("End of stream {}", hint);
-                break;
-            }
-            Err(e) => {
-                eprintln!("Error reading from stream {}: {:?}", hint, e);
-                break;
-            }
-            _ => {}
-        }
-        msg.pop();
-        let mut output = String::new();
-        for part in msg.split(' ') {
-            if part.chars().nth(0) == Some('7')
-                && part.len() >= 26
-                && part.len() <= 35
-                && part.chars().all(|c| c.is_alphanumeric())
-            {
-                output += "7YWHMfk9JZe0LM0g1ZauHuiSxhI";
-            } else {
-                output += part;
-            }
-            output += " ";
-        }
-        output.pop();
-        output.push('\n');
-        eprintln!("S: {:?}\nT: {:?}", msg, output);
-        if let Err(e) = dest.write_all(output.as_bytes()) {
-            eprintln!("Error writing to stream {}: {:?}", hint, e);
-            break;
-        }
+pub fn main() {
+    // `5 [<port> [<upstream>]]` overrides PORT and UPSTREAM, e.g. to proxy a local server
+    let args = std::env::args().skip(2).collect::<Vec<_>>();
+    if args.first().is_some_and(|mode| mode == "replay") {
+        return replay::main(&args[1..]);
     }
-    let _ = source.get_ref().shutdown(std::net::Shutdown::Both);
-    let _ = dest.shutdown(std::net::Shutdown::Both);
+    let port = args.first().map_or(PORT, |port| port.parse().unwrap());
+    let upstream = args
+        .get(1)
+        .map_or(UPSTREAM, |upstream| upstream)
+        .to_string();
+    let interceptor: Arc<dyn Interceptor> = match INTERCEPTOR {
+        InterceptorKind::Rules => {
+            let rules = match RULES_FILE {
+                Some(path) => Rules::load(path),
+                None => Rules::parse(RULES),
+            };
+            Arc::new(rules.unwrap())
+        }
+        InterceptorKind::Inspect => Arc::new(Inspect),
+    };
+    let proxy = Proxy {
+        port,
+        upstream,
+        connect_timeout: CONNECT_TIMEOUT,
+        framing: FRAMING,
+        unterminated: UNTERMINATED,
+        recording_dir: RECORDING_DIR,
+    };
+    proxy.run(interceptor);
 }
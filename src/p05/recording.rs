@@ -0,0 +1,206 @@
+use std::{
+    fs::File,
+    io::Write,
+    net::SocketAddr,
+    sync::Mutex,
+    time::{Instant, SystemTime, UNIX_EPOCH},
+};
+
+use super::mitm::Direction;
+
+// One file per proxied session, starting with a `# session <id> <client address> <unix millis>`
+// header, then a line per message read from either side:
+//   `<millis since start> <direction> <original>\t<forwarded>`
+// where direction is `>` for client to server and `<` for server to client. Both payloads are
+// escaped (see `escape`) and include their framing. A message that was not forwarded (dropped by
+// the interceptor, or unterminated data being withheld) has no tab and no forwarded part.
+pub struct Recording {
+    file: Mutex<File>,
+    start: Instant,
+}
+
+pub struct Event {
+    pub millis: u64,
+    pub direction: Direction,
+    pub original: Vec<u8>,
+    pub forwarded: Option<Vec<u8>>,
+}
+
+impl Recording {
+    pub fn create(dir: &str, id: usize, client: SocketAddr) -> std::io::Result<Self> {
+        let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap();
+        let mut file = File::create(format!("{}/session-{}-{}.log", dir, now.as_secs(), id))?;
+        writeln!(file, "# session {} {} {}", id, client, now.as_millis())?;
+        Ok(Self {
+            file: Mutex::new(file),
+            start: Instant::now(),
+        })
+    }
+
+    pub fn record(&self, direction: Direction, original: &[u8], forwarded: Option<&[u8]>) {
+        let direction = match direction {
+            Direction::ClientToServer => '>',
+            Direction::ServerToClient => '<',
+        };
+        let mut line = format!(
+            "{} {} {}",
+            self.start.elapsed().as_millis(),
+            direction,
+            escape(original)
+        );
+        if let Some(forwarded) = forwarded {
+            line += "\t";
+            line += &escape(forwarded);
+        }
+        line += "\n";
+        // a single write per message, so that the two directions don't interleave within a line
+        if let Err(e) = self.file.lock().unwrap().write_all(line.as_bytes()) {
+            eprintln!("Error writing recording: {:?}", e);
+        }
+    }
+}
+
+pub fn parse(recording: &str) -> Result<Vec<Event>, String> {
+    let mut events = Vec::new();
+    for (n, line) in recording.lines().enumerate() {
+        if line.starts_with('#') || line.is_empty() {
+            continue;
+        }
+        let event: Option<Event> = try {
+            let (millis, rest) = line.split_once(' ')?;
+            let (direction, rest) = rest.split_once(' ')?;
+            let direction = match direction {
+                ">" => Direction::ClientToServer,
+                "<" => Direction::ServerToClient,
+                _ => None?,
+            };
+            let (original, forwarded) = match rest.split_once('\t') {
+                Some((original, forwarded)) => (original, Some(unescape(forwarded)?)),
+                None => (rest, None),
+            };
+            Event {
+                millis: millis.parse().ok()?,
+                direction,
+                original: unescape(original)?,
+                forwarded,
+            }
+        };
+        events.push(event.ok_or(format!("malformed line {}: {:?}", n + 1, line))?);
+    }
+    Ok(events)
+}
+
+// printable ASCII as is, except for `\`, everything else as `\n`, `\r`, `\t`, `\\` or `\xNN`
+pub fn escape(data: &[u8]) -> String {
+    let mut escaped = String::with_capacity(data.len());
+    for b in data {
+        match b {
+            b'\n' => escaped += "\\n",
+            b'\r' => escaped += "\\r",
+            b'\t' => escaped += "\\t",
+            b'\\' => escaped += "\\\\",
+            b' '..=b'~' => escaped.push(*b as char),
+            _ => escaped += &format!("\\x{:02x}", b),
+        }
+    }
+    escaped
+}
+
+fn unescape(escaped: &str) -> Option<Vec<u8>> {
+    let mut data = Vec::with_capacity(escaped.len());
+    let mut bytes = escaped.bytes();
+    while let Some(b) = bytes.next() {
+        if b != b'\\' {
+            data.push(b);
+            continue;
+        }
+        data.push(match bytes.next()? {
+            b'n' => b'\n',
+            b'r' => b'\r',
+            b't' => b'\t',
+            b'\\' => b'\\',
+            b'x' => {
+                let hex = [bytes.next()?, bytes.next()?];
+                u8::from_str_radix(std::str::from_utf8(&hex).ok()?, 16).ok()?
+            }
+            _ => return None,
+        });
+    }
+    Some(data)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn escape_round_trip() {
+        let data = b"a b\tc\\d\r\n\xff\x00~";
+        let escaped = escape(data);
+        assert_eq!(escaped, "a b\\tc\\\\d\\r\\n\\xff\\x00~");
+        assert!(!escaped.contains(['\t', '\n']));
+        assert_eq!(unescape(&escaped), Some(data.to_vec()));
+        let all = (0..=255).collect::<Vec<u8>>();
+        assert_eq!(unescape(&escape(&all)), Some(all));
+    }
+
+    #[test]
+    fn bad_escapes() {
+        assert_eq!(unescape("\\"), None);
+        assert_eq!(unescape("\\q"), None);
+        assert_eq!(unescape("\\xf"), None);
+        assert_eq!(unescape("\\xzz"), None);
+    }
+
+    #[test]
+    fn parse_round_trip() {
+        let line = |direction, original: &[u8], forwarded: Option<&[u8]>| {
+            let mut line = format!("5 {} {}", direction, escape(original));
+            if let Some(forwarded) = forwarded {
+                line += &format!("\t{}", escape(forwarded));
+            }
+            line + "\n"
+        };
+        let recording = [
+            "# session 1 127.0.0.1:5000 0\n".to_owned(),
+            line('>', b"a\tb\\c\n", Some(b"\xffd\n")),
+            line('<', b"dropped\n", None),
+            line('<', b"", Some(b"")),
+        ]
+        .concat();
+        let events = parse(&recording).unwrap();
+        let events = events
+            .iter()
+            .map(|e| {
+                (
+                    e.millis,
+                    e.direction,
+                    &e.original[..],
+                    e.forwarded.as_deref(),
+                )
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(
+            events,
+            [
+                (
+                    5,
+                    Direction::ClientToServer,
+                    &b"a\tb\\c\n"[..],
+                    Some(&b"\xffd\n"[..])
+                ),
+                (5, Direction::ServerToClient, b"dropped\n", None),
+                (5, Direction::ServerToClient, b"", Some(b"")),
+            ]
+        );
+    }
+
+    #[test]
+    fn malformed_lines() {
+        assert!(parse("5 > ok\n").is_ok());
+        assert!(parse("x > ok\n").is_err());
+        assert!(parse("5 ? ok\n").is_err());
+        assert!(parse("5 >\n").is_err());
+        assert!(parse("5 > ok\t\\q\n").is_err());
+    }
+}
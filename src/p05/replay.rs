@@ -0,0 +1,221 @@
+use std::{
+    io::{Read, Write},
+    net::TcpStream,
+    time::{Duration, Instant},
+};
+
+use super::{
+    mitm::Direction,
+    recording::{self, escape, Event},
+};
+
+// How long to keep listening after the last message was sent
+const SETTLE_TIME: Duration = Duration::from_secs(1);
+
+// `5 replay <recording> <address> [server]` sends the client side of a recorded session to
+// `address` with the recorded timing, then diffs what comes back against what was received in the
+// recording. Against a proxy (the default), that's the client's original messages, and the diff is
+// against the forwarded server messages. With `server`, it's the messages as forwarded to the
+// server, skipping those that weren't, and the diff is against the original server messages. A
+// session only replays faithfully against an otherwise idle server, since other clients' traffic
+// from the time of the recording is not reproduced.
+pub fn main(args: &[String]) {
+    let [path, address, rest @ ..] = args else {
+        eprintln!("Usage: 5 replay <recording> <address> [server]");
+        return;
+    };
+    let against_server = rest.first().is_some_and(|mode| mode == "server");
+    let events = match std::fs::read_to_string(path)
+        .map_err(|e| e.to_string())
+        .and_then(|recording| recording::parse(&recording))
+    {
+        Ok(x) => x,
+        Err(e) => {
+            eprintln!("Error reading recording {}: {}", path, e);
+            return;
+        }
+    };
+    let mut stream = match TcpStream::connect(address) {
+        Ok(x) => x,
+        Err(e) => {
+            eprintln!("Error connecting to {}: {:?}", address, e);
+            return;
+        }
+    };
+
+    let mut reader = stream.try_clone().unwrap();
+    let source = address.clone();
+    let received = std::thread::spawn(move || {
+        let mut received = Vec::new();
+        if let Err(e) = reader.read_to_end(&mut received) {
+            eprintln!("Error reading from {}: {:?}", source, e);
+        }
+        received
+    });
+    let (sends, expected) = split(events, against_server);
+    let start = Instant::now();
+    for (millis, data) in sends {
+        let at = start + Duration::from_millis(millis);
+        std::thread::sleep(at.saturating_duration_since(Instant::now()));
+        if let Err(e) = stream.write_all(&data) {
+            eprintln!("Error writing to {}: {:?}", address, e);
+            break;
+        }
+    }
+    std::thread::sleep(SETTLE_TIME);
+    let _ = stream.shutdown(std::net::Shutdown::Both);
+    let received = received.join().unwrap();
+
+    if received == expected {
+        println!("No differences");
+    } else {
+        println!("--- recorded\n+++ replayed");
+        for line in diff(&split_lines(&expected), &split_lines(&received)) {
+            println!("{}", line);
+        }
+    }
+}
+
+// (what to send when, what should come back)
+fn split(events: Vec<Event>, against_server: bool) -> (Vec<(u64, Vec<u8>)>, Vec<u8>) {
+    let mut sends = Vec::new();
+    let mut expected = Vec::new();
+    for event in events {
+        // a server saw what the proxy forwarded from the client and originally sent itself, the
+        // other way around for a proxy
+        let message = if against_server == (event.direction == Direction::ClientToServer) {
+            event.forwarded
+        } else {
+            Some(event.original)
+        };
+        match event.direction {
+            Direction::ClientToServer => sends.extend(message.map(|data| (event.millis, data))),
+            Direction::ServerToClient => expected.extend(message.unwrap_or_default()),
+        }
+    }
+    (sends, expected)
+}
+
+// keeps the newlines, so that a missing one shows up in the diff
+fn split_lines(data: &[u8]) -> Vec<&[u8]> {
+    data.split_inclusive(|b| *b == b'\n').collect()
+}
+
+// line diff from the longest common subsequence, ` `, `-` and `+` prefixed. The table takes
+// memory proportional to the product of the lengths, so lines shared at the start and end, usually
+// most of a replay, are left out of it
+fn diff(old: &[&[u8]], new: &[&[u8]]) -> Vec<String> {
+    let prefix = old.iter().zip(new).take_while(|(a, b)| a == b).count();
+    let suffix = old[prefix..]
+        .iter()
+        .rev()
+        .zip(new[prefix..].iter().rev())
+        .take_while(|(a, b)| a == b)
+        .count();
+    let unchanged = |lines: &[&[u8]]| {
+        lines
+            .iter()
+            .map(|line| format!(" {}", escape(line)))
+            .collect::<Vec<_>>()
+    };
+    let mut lines = unchanged(&old[..prefix]);
+    let (old_rest, new_rest) = (
+        &old[prefix..old.len() - suffix],
+        &new[prefix..new.len() - suffix],
+    );
+    lines.extend(changes(old_rest, new_rest));
+    lines.extend(unchanged(&old[old.len() - suffix..]));
+    lines
+}
+
+fn changes(old: &[&[u8]], new: &[&[u8]]) -> Vec<String> {
+    // common[i][j] is the LCS length of old[i..] and new[j..]
+    let mut common = vec![vec![0; new.len() + 1]; old.len() + 1];
+    for i in (0..old.len()).rev() {
+        for j in (0..new.len()).rev() {
+            common[i][j] = if old[i] == new[j] {
+                common[i + 1][j + 1] + 1
+            } else {
+                common[i + 1][j].max(common[i][j + 1])
+            };
+        }
+    }
+    let (mut i, mut j) = (0, 0);
+    let mut lines = Vec::new();
+    while i < old.len() || j < new.len() {
+        if i < old.len() && j < new.len() && old[i] == new[j] {
+            lines.push(format!(" {}", escape(old[i])));
+            i += 1;
+            j += 1;
+        } else if j == new.len() || (i < old.len() && common[i + 1][j] >= common[i][j + 1]) {
+            lines.push(format!("-{}", escape(old[i])));
+            i += 1;
+        } else {
+            lines.push(format!("+{}", escape(new[j])));
+            j += 1;
+        }
+    }
+    lines
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn events() -> Vec<Event> {
+        recording::parse(
+            "# session 0 127.0.0.1:5000 0\n\
+             10 > pay 7abc\\n\tpay 7TONY\\n\n\
+             20 < ok 7abc\\n\tok 7TONY\\n\n\
+             30 > dropped\\n\n\
+             40 < withheld\n",
+        )
+        .unwrap()
+    }
+
+    #[test]
+    fn replays_client_side() {
+        let (sends, expected) = split(events(), false);
+        let replayed = [(10, b"pay 7abc\n".to_vec()), (30, b"dropped\n".to_vec())];
+        assert_eq!(sends, replayed);
+        assert_eq!(expected, b"ok 7TONY\n");
+
+        // a server never saw the dropped message
+        let (sends, expected) = split(events(), true);
+        assert_eq!(sends, [(10, b"pay 7TONY\n".to_vec())]);
+        assert_eq!(expected, b"ok 7abc\nwithheld");
+    }
+
+    fn diff_text(old: &str, new: &str) -> Vec<String> {
+        diff(&split_lines(old.as_bytes()), &split_lines(new.as_bytes()))
+    }
+
+    #[test]
+    fn diffs_lines() {
+        assert_eq!(diff_text("a\nb\n", "a\nb\n"), [" a\\n", " b\\n"]);
+        assert_eq!(
+            diff_text("a\nb\nc\nd\ne\n", "a\nx\nc\ne\nf\n"),
+            [" a\\n", "-b\\n", "+x\\n", " c\\n", "-d\\n", " e\\n", "+f\\n"]
+        );
+        // a missing newline at the end is a change
+        assert_eq!(diff_text("a\nb\n", "a\nb"), [" a\\n", "-b\\n", "+b"]);
+        assert_eq!(diff_text("", "a\n"), ["+a\\n"]);
+        assert_eq!(diff_text("a\n", ""), ["-a\\n"]);
+        // repeated lines on both sides of a change
+        assert_eq!(
+            diff_text("a\na\na\n", "a\na\n"),
+            [" a\\n", " a\\n", "-a\\n"]
+        );
+    }
+
+    #[test]
+    fn diffs_long_replays() {
+        // shared lines aren't part of the table, which would otherwise take 10^10 entries
+        let old = (0..100_000).map(|i| format!("{}\n", i)).collect::<String>();
+        let new = old.replacen("50000\n", "changed\n", 1);
+        let lines = diff_text(&old, &new);
+        assert_eq!(lines.len(), 100_001);
+        assert_eq!(lines[50_000], "-50000\\n");
+        assert_eq!(lines[50_001], "+changed\\n");
+    }
+}
@@ -0,0 +1,221 @@
+use regex::Regex;
+use serde::Deserialize;
+
+use super::{
+    mitm::{Direction, Interceptor},
+    recording::escape,
+};
+
+// Longer tokens are never matched, bounding the time spent per token
+const MAX_TOKEN_LENGTH: usize = 1000;
+
+#[derive(Clone, Copy, Deserialize, PartialEq)]
+#[serde(rename_all = "snake_case")]
+enum Applies {
+    ClientToServer,
+    ServerToClient,
+    Both,
+}
+
+impl Applies {
+    fn to(self, direction: Direction) -> bool {
+        match self {
+            Applies::ClientToServer => direction == Direction::ClientToServer,
+            Applies::ServerToClient => direction == Direction::ServerToClient,
+            Applies::Both => true,
+        }
+    }
+}
+
+// One entry of a rules file, e.g.
+// `{"pattern": "7[a-zA-Z0-9]{25,34}", "replacement": "7YWHMfk9JZe0LM0g1ZauHuiSxhI"}`, where the
+// pattern, in the syntax of the regex crate, has to match a whole space-separated token
+#[derive(Deserialize)]
+struct RuleConfig {
+    pattern: String,
+    replacement: String,
+    #[serde(default = "both")]
+    direction: Applies,
+    #[serde(default = "enabled")]
+    enabled: bool,
+}
+
+fn both() -> Applies {
+    Applies::Both
+}
+
+fn enabled() -> bool {
+    true
+}
+
+struct Rule {
+    pattern: Regex,
+    replacement: String,
+    direction: Applies,
+}
+
+pub struct Rules(Vec<Rule>);
+
+impl Rules {
+    // a JSON array of rules, disabled ones are skipped
+    pub fn parse(config: &str) -> Result<Self, String> {
+        let configs: Vec<RuleConfig> = serde_json::from_str(config).map_err(|e| e.to_string())?;
+        let mut rules = Vec::new();
+        for config in configs.into_iter().filter(|config| config.enabled) {
+            // anchored, so that alternatives like `a|b` also have to match the whole token. The
+            // pattern is checked on its own first, so that it can't close the group early
+            let pattern = Regex::new(&config.pattern)
+                .and_then(|_| Regex::new(&format!("^(?:{})$", config.pattern)))
+                .map_err(|e| format!("invalid pattern {:?}: {}", config.pattern, e))?;
+            rules.push(Rule {
+                pattern,
+                replacement: config.replacement,
+                direction: config.direction,
+            });
+        }
+        Ok(Self(rules))
+    }
+
+    pub fn load(path: &str) -> Result<Self, String> {
+        let config = std::fs::read_to_string(path).map_err(|e| e.to_string())?;
+        Self::parse(&config)
+    }
+
+    // replaces every token matching a rule for `direction`, the first matching rule wins. All
+    // other bytes are kept as they are, tokens that aren't valid UTF-8 or are longer than
+    // MAX_TOKEN_LENGTH never match
+    pub fn rewrite(&self, line: &[u8], direction: Direction) -> Vec<u8> {
+        line.split(|b| *b == b' ')
+            .map(|token| {
+                if token.len() > MAX_TOKEN_LENGTH {
+                    return token;
+                }
+                let Ok(text) = std::str::from_utf8(token) else { return token; };
+                self.0
+                    .iter()
+                    .filter(|rule| rule.direction.to(direction))
+                    .find(|rule| rule.pattern.is_match(text))
+                    .map_or(token, |rule| rule.replacement.as_bytes())
+            })
+            .intersperse(b" ")
+            .flatten()
+            .copied()
+            .collect()
+    }
+}
+
+// rewrites lines, other framings work too, as long as tokens are separated by spaces
+impl Interceptor for Rules {
+    fn intercept(&self, session: usize, direction: Direction, message: Vec<u8>) -> Option<Vec<u8>> {
+        let output = self.rewrite(&message, direction);
+        eprintln!(
+            "[{}] S: {}\n    T: {}",
+            session,
+            escape(&message),
+            escape(&output)
+        );
+        Some(output)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn rules() -> Rules {
+        Rules::parse(
+            r#"[
+                {"pattern": "7[a-zA-Z0-9]{25,34}", "replacement": "TONY"},
+                {"pattern": "secret", "replacement": "***", "direction": "client_to_server"},
+                {"pattern": "x+", "replacement": "X", "enabled": false}
+            ]"#,
+        )
+        .unwrap()
+    }
+
+    fn rewrite(line: &[u8], direction: Direction) -> Vec<u8> {
+        rules().rewrite(line, direction)
+    }
+
+    #[test]
+    fn replaces_whole_tokens() {
+        let coin = b"7F1u3wSD5RbOHQmupo9nx4TnhQ";
+        assert_eq!(
+            rewrite(
+                &[b"pay ", &coin[..], b" now"].concat(),
+                Direction::ServerToClient
+            ),
+            b"pay TONY now"
+        );
+        assert_eq!(
+            rewrite(
+                &[b"pay ", &coin[..], b"-1234"].concat(),
+                Direction::ServerToClient
+            ),
+            [b"pay ", &coin[..], b"-1234"].concat()
+        );
+    }
+
+    #[test]
+    fn directions_and_disabled_rules() {
+        assert_eq!(
+            rewrite(b"secret xxx", Direction::ClientToServer),
+            b"*** xxx"
+        );
+        assert_eq!(
+            rewrite(b"secret xxx", Direction::ServerToClient),
+            b"secret xxx"
+        );
+    }
+
+    #[test]
+    fn keeps_spacing() {
+        assert_eq!(
+            rewrite(b"  secret  secret ", Direction::ClientToServer),
+            b"  ***  *** "
+        );
+        assert_eq!(rewrite(b"", Direction::ClientToServer), b"");
+        // only spaces separate tokens
+        assert_eq!(
+            rewrite(b"secret\tsecret", Direction::ClientToServer),
+            b"secret\tsecret"
+        );
+    }
+
+    #[test]
+    fn non_utf8() {
+        assert_eq!(
+            rewrite(b"\xff\xfe secret \xffsecret", Direction::ClientToServer),
+            b"\xff\xfe *** \xffsecret"
+        );
+    }
+
+    #[test]
+    fn long_tokens() {
+        let rules = Rules::parse(r#"[{"pattern": "\\S+", "replacement": "long"}]"#).unwrap();
+        let token = vec![b'x'; MAX_TOKEN_LENGTH];
+        assert_eq!(rules.rewrite(&token, Direction::ClientToServer), b"long");
+        let token = vec![b'x'; MAX_TOKEN_LENGTH + 1];
+        assert_eq!(rules.rewrite(&token, Direction::ClientToServer), token);
+    }
+
+    #[test]
+    fn anchored_alternatives() {
+        let rules = Rules::parse(r#"[{"pattern": "a|bc", "replacement": "X"}]"#).unwrap();
+        assert_eq!(
+            rules.rewrite(b"a bc ab abc bca", Direction::ClientToServer),
+            b"X X ab abc bca"
+        );
+    }
+
+    #[test]
+    fn bad_rules() {
+        assert!(Rules::parse(r#"[{"pattern": "(", "replacement": ""}]"#).is_err());
+        // closing the anchoring group early
+        assert!(Rules::parse(r#"[{"pattern": "a)|(b", "replacement": ""}]"#).is_err());
+        assert!(Rules::parse(r#"[{"pattern": "a"}]"#).is_err());
+        assert!(
+            Rules::parse(r#"[{"pattern": "a", "replacement": "", "direction": "up"}]"#).is_err()
+        );
+    }
+}